@@ -10,13 +10,18 @@
 
 use std::{
     fmt::{self, Display},
-    process::Stdio,
+    future::Future,
+    process::{Output, Stdio},
 };
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use tokio::{process::Command, sync::OnceCell};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+    sync::OnceCell,
+};
 
 use tracing::instrument;
 
@@ -39,6 +44,28 @@ pub struct NixCmd {
 
 static NIXCMD: OnceCell<NixCmd> = OnceCell::const_new();
 
+tokio::task_local! {
+    static LOG_PREFIX: String;
+}
+
+/// Run the given future such that log output produced within it is prefixed with `prefix`.
+///
+/// This applies to the stderr of processes spawned by [NixCmd::run_with] (and
+/// [spawn_and_wait_with_output]). Log formatters may use [log_prefix] to do the
+/// same for tracing events. Useful when running several Nix commands
+/// concurrently, so that their output can be told apart.
+pub async fn with_log_prefix<F>(prefix: String, f: F) -> F::Output
+where
+    F: Future,
+{
+    LOG_PREFIX.scope(prefix, f).await
+}
+
+/// The log prefix set by the enclosing [with_log_prefix], if any.
+pub fn log_prefix() -> Option<String> {
+    LOG_PREFIX.try_with(|prefix| prefix.clone()).ok()
+}
+
 /// Trace a user-copyable command line
 ///
 /// [tracing::info!] the given [tokio::process::Command] with human-readable
@@ -58,6 +85,53 @@ pub fn trace_cmd_with(icon: &str, cmd: &tokio::process::Command) {
     tracing::info!("{}", format!("{} {}️", icon, to_cli(cmd)).dimmed());
 }
 
+/// Spawn the given [Command], waiting for it to finish.
+///
+/// If a log prefix is set (see [with_log_prefix]), the stderr of the process is
+/// forwarded line by line with that prefix; it is also made available in the
+/// returned [Output]. Otherwise, this is equivalent to
+/// [tokio::process::Child::wait_with_output].
+pub async fn spawn_and_wait_with_output(cmd: &mut Command) -> std::io::Result<Output> {
+    match log_prefix() {
+        None => cmd.spawn()?.wait_with_output().await,
+        Some(prefix) => {
+            cmd.stderr(Stdio::piped());
+            let mut child = cmd.spawn()?;
+            let stderr = child.stderr.take();
+            let (out, stderr) = tokio::join!(
+                child.wait_with_output(),
+                forward_with_prefix(&prefix, stderr)
+            );
+            let mut out = out?;
+            out.stderr = stderr?;
+            Ok(out)
+        }
+    }
+}
+
+/// Write each line read from `reader` to our stderr, prefixed with `prefix`, returning everything read.
+async fn forward_with_prefix<R>(prefix: &str, reader: Option<R>) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut all = vec![];
+    let Some(reader) = reader else {
+        return Ok(all);
+    };
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    // NOTE: We read bytes (not `String`s) because build logs may contain bad UTF-8.
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        eprintln!(
+            "{} {}",
+            prefix,
+            String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n'])
+        );
+        all.append(&mut line);
+    }
+    Ok(all)
+}
+
 impl NixCmd {
     /// Return a global `NixCmd` instance with flakes enabled.
     pub async fn get() -> &'static NixCmd {
@@ -164,22 +238,29 @@ impl NixCmd {
     {
         let mut cmd = self.command(subcommands);
         f(&mut cmd);
-        trace_cmd(&cmd);
-        let out = cmd.spawn()?.wait_with_output().await?;
-        if out.status.success() {
-            Ok(out.stdout)
-        } else {
-            let stderr = String::from_utf8_lossy(&out.stderr).to_string();
-            Err(CommandError::ProcessFailed {
-                stderr,
-                exit_code: out.status.code(),
-            })
-        }
+        run_command(&mut cmd).await
+    }
+}
+
+/// Run the given [Command] (typically created by [NixCmd::command]), while also tracing it.
+///
+/// See [NixCmd::run_with].
+pub async fn run_command(cmd: &mut Command) -> Result<Vec<u8>, CommandError> {
+    trace_cmd(cmd);
+    let out = spawn_and_wait_with_output(cmd).await?;
+    if out.status.success() {
+        Ok(out.stdout)
+    } else {
+        let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+        Err(CommandError::ProcessFailed {
+            stderr,
+            exit_code: out.status.code(),
+        })
     }
 }
 
 /// Convert a Command to user-copyable CLI string
-fn to_cli(cmd: &tokio::process::Command) -> String {
+pub fn to_cli(cmd: &tokio::process::Command) -> String {
     use std::ffi::OsStr;
     let program = cmd.as_std().get_program().to_string_lossy().to_string();
     let args = cmd
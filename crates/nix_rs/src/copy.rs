@@ -35,10 +35,10 @@ where
     cmd.run_with(&["copy"], |cmd| {
         cmd.arg("-v");
         if let Some(uri) = options.from {
-            cmd.arg("--from").arg(uri.to_string());
+            uri.use_in_command("--from", cmd);
         }
         if let Some(uri) = options.to {
-            cmd.arg("--to").arg(uri.to_string());
+            uri.use_in_command("--to", cmd);
         }
         if options.no_check_sigs {
             cmd.arg("--no-check-sigs");
@@ -48,3 +48,32 @@ where
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::command::NixStoreCmd;
+
+    #[tokio::test]
+    async fn test_nix_copy_to_file_binary_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("hello.txt");
+        std::fs::write(&file, "Hello, binary cache").unwrap();
+        let path = NixStoreCmd.nix_store_add(&file).await.unwrap();
+
+        let cache = tmp.path().join("cache");
+        let to: StoreURI = format!("file://{}", cache.display()).parse().unwrap();
+        let options = NixCopyOptions {
+            to: Some(to),
+            ..Default::default()
+        };
+        nix_copy(&NixCmd::default(), options, [path.as_path()])
+            .await
+            .unwrap();
+
+        let hash = path.as_path().file_name().unwrap().to_string_lossy();
+        let hash = hash.split('-').next().unwrap();
+        assert!(cache.join("nix-cache-info").exists());
+        assert!(cache.join(format!("{}.narinfo", hash)).exists());
+    }
+}
@@ -0,0 +1,591 @@
+//! Structured progress from Nix, using `--log-format internal-json`
+//!
+//! With this log format, Nix writes one JSON object per line to stderr (prefixed
+//! with `@nix `), describing the start and end of "activities" (builds,
+//! downloads, ...), their results (build log lines, phases, progress), and log
+//! messages. [EventParser] turns these into [NixEvent]s; use
+//! [NixCmd::run_with_events] to run a Nix command while receiving them.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use nix_rs::command::NixCmd;
+//! let cmd = NixCmd::default();
+//! cmd.run_with_events(&["build"], |c| { c.arg("nixpkgs#hello"); }, |event| {
+//!     println!("{:?}", event);
+//! }).await?;
+//! ```
+use std::{collections::HashMap, path::PathBuf, process::Stdio};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::Command,
+};
+
+use crate::command::{log_prefix, trace_cmd, CommandError, NixCmd};
+
+/// An event of interest reported by Nix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    tag = "event",
+    rename_all = "kebab-case",
+    rename_all_fields = "camelCase"
+)]
+pub enum NixEvent {
+    /// A derivation started building
+    BuildStarted {
+        /// The derivation being built
+        drv_path: PathBuf,
+        /// The (remote) machine it is built on, if not the local one
+        machine: Option<String>,
+    },
+    /// A derivation is no longer building (whether it succeeded or not; see [NixEvent::DerivationFailed])
+    BuildFinished {
+        /// The derivation that was built
+        drv_path: PathBuf,
+    },
+    /// A build entered a new phase (e.g. `buildPhase`)
+    BuildPhase {
+        /// The derivation being built
+        drv_path: PathBuf,
+        /// Name of the phase
+        phase: String,
+    },
+    /// A line of build log
+    BuildLogLine {
+        /// The derivation being built
+        drv_path: PathBuf,
+        /// The log line
+        line: String,
+    },
+    /// A download (e.g. of a NAR from a binary cache) started
+    DownloadStarted {
+        /// The URL being downloaded
+        url: String,
+    },
+    /// A download finished
+    DownloadFinished {
+        /// The URL that was downloaded
+        url: String,
+    },
+    /// A store path is being fetched from a substituter, instead of being built
+    Substituting {
+        /// The store path being substituted
+        store_path: PathBuf,
+        /// The substituter it is fetched from
+        from: String,
+    },
+    /// Progress of an activity
+    ///
+    /// For downloads, the numbers are in bytes; for [ActivityType::Builds], they
+    /// are numbers of builds, etc.
+    Progress {
+        /// Identifier of the activity, unique within a Nix invocation
+        id: u64,
+        /// The type of the activity
+        activity: ActivityType,
+        /// Amount done
+        done: u64,
+        /// Amount expected in total
+        expected: u64,
+        /// Amount in progress
+        running: u64,
+        /// Amount that failed
+        failed: u64,
+    },
+    /// Building a derivation failed
+    DerivationFailed {
+        /// The derivation that failed to build
+        drv_path: PathBuf,
+        /// The error message
+        message: String,
+    },
+    /// Any other log message
+    Message {
+        /// Verbosity of the message (0 for errors, 1 for warnings, ...)
+        level: u8,
+        /// The message
+        message: String,
+    },
+}
+
+/// Type of a Nix activity
+///
+/// See `ActivityType` in Nix's `logging.hh`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActivityType {
+    /// Copying a single store path
+    CopyPath,
+    /// Downloading (or uploading) a file
+    FileTransfer,
+    /// Realising store paths
+    Realise,
+    /// Copying store paths
+    CopyPaths,
+    /// Building derivations
+    Builds,
+    /// Building a single derivation
+    Build,
+    /// Optimising the store
+    OptimiseStore,
+    /// Verifying store paths
+    VerifyPaths,
+    /// Substituting a store path
+    Substitute,
+    /// Querying path info
+    QueryPathInfo,
+    /// Running the post-build hook
+    PostBuildHook,
+    /// Waiting for a build slot
+    BuildWaiting,
+    /// Fetching a flake input, or other source tree
+    FetchTree,
+    /// An activity type not known to us
+    Unknown,
+}
+
+impl From<u64> for ActivityType {
+    fn from(n: u64) -> Self {
+        match n {
+            100 => ActivityType::CopyPath,
+            101 => ActivityType::FileTransfer,
+            102 => ActivityType::Realise,
+            103 => ActivityType::CopyPaths,
+            104 => ActivityType::Builds,
+            105 => ActivityType::Build,
+            106 => ActivityType::OptimiseStore,
+            107 => ActivityType::VerifyPaths,
+            108 => ActivityType::Substitute,
+            109 => ActivityType::QueryPathInfo,
+            110 => ActivityType::PostBuildHook,
+            111 => ActivityType::BuildWaiting,
+            112 => ActivityType::FetchTree,
+            _ => ActivityType::Unknown,
+        }
+    }
+}
+
+/// Result types (see `ResultType` in Nix's `logging.hh`) we are interested in
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+const RESULT_SET_PHASE: u64 = 104;
+const RESULT_PROGRESS: u64 = 105;
+const RESULT_POST_BUILD_LOG_LINE: u64 = 107;
+
+/// A line of `--log-format internal-json` output (sans the `@nix ` prefix)
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum LogLine {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        activity_type: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        result_type: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Msg {
+        level: u8,
+        msg: String,
+    },
+}
+
+/// An activity that was started, but not yet stopped
+struct Activity {
+    activity_type: ActivityType,
+    fields: Vec<Value>,
+}
+
+impl Activity {
+    fn field_str(&self, i: usize) -> Option<&str> {
+        self.fields.get(i).and_then(Value::as_str)
+    }
+}
+
+lazy_static! {
+    /// Matches the error messages of Nix when a derivation fails to build
+    ///
+    /// The path may be highlighted using ANSI escape sequences.
+    static ref DRV_FAILED: Regex = Regex::new(
+        r"(?:builder for|Cannot build) '(?:\x1b\[[0-9;]*m)*(/[^'\x1b]+\.drv)(?:\x1b\[[0-9;]*m)*'"
+    )
+    .unwrap();
+
+    /// Matches the error messages of Nix when rebuilding a derivation produces a different output
+    static ref NOT_DETERMINISTIC: Regex = Regex::new(
+        r"derivation '(?:\x1b\[[0-9;]*m)*(/[^'\x1b]+\.drv)(?:\x1b\[[0-9;]*m)*' may not be deterministic"
+    )
+    .unwrap();
+}
+
+/// Find the derivation that failed to build in the given Nix error message, if any
+pub fn failed_derivation(message: &str) -> Option<PathBuf> {
+    DRV_FAILED
+        .captures(message)
+        .map(|captures| PathBuf::from(&captures[1]))
+}
+
+/// Find the derivation reported as not deterministic (by `nix build --rebuild`) in the given Nix error message, if any
+pub fn non_deterministic_derivation(message: &str) -> Option<PathBuf> {
+    NOT_DETERMINISTIC
+        .captures(message)
+        .map(|captures| PathBuf::from(&captures[1]))
+}
+
+/// A line of Nix's stderr, as interpreted by [EventParser::parse_line]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedLine {
+    /// A line in the `internal-json` format, reporting an event of interest
+    Event(NixEvent),
+    /// A line in the `internal-json` format, but of no interest
+    Nothing,
+    /// A line not in the `internal-json` format (Nix may still print such lines, e.g. from `nix run`)
+    Raw,
+}
+
+/// Parses lines of `--log-format internal-json` into [NixEvent]s
+///
+/// Stateful, since most events are reported in relation to an earlier activity.
+#[derive(Default)]
+pub struct EventParser {
+    activities: HashMap<u64, Activity>,
+}
+
+impl EventParser {
+    /// Parse a line of Nix's stderr
+    pub fn parse_line(&mut self, line: &str) -> ParsedLine {
+        let Some(json) = line.strip_prefix("@nix ") else {
+            return ParsedLine::Raw;
+        };
+        match serde_json::from_str::<LogLine>(json) {
+            Ok(log_line) => self
+                .event(log_line)
+                .map_or(ParsedLine::Nothing, ParsedLine::Event),
+            Err(err) => {
+                tracing::debug!("Unable to parse Nix log line {:?}: {}", json, err);
+                ParsedLine::Nothing
+            }
+        }
+    }
+
+    fn event(&mut self, log_line: LogLine) -> Option<NixEvent> {
+        match log_line {
+            LogLine::Start {
+                id,
+                activity_type,
+                fields,
+            } => {
+                let activity = Activity {
+                    activity_type: activity_type.into(),
+                    fields,
+                };
+                let event = match activity.activity_type {
+                    ActivityType::Build => Some(NixEvent::BuildStarted {
+                        drv_path: activity.field_str(0)?.into(),
+                        machine: activity
+                            .field_str(1)
+                            .filter(|m| !m.is_empty())
+                            .map(String::from),
+                    }),
+                    ActivityType::FileTransfer => Some(NixEvent::DownloadStarted {
+                        url: activity.field_str(0)?.to_string(),
+                    }),
+                    ActivityType::Substitute => Some(NixEvent::Substituting {
+                        store_path: activity.field_str(0)?.into(),
+                        from: activity.field_str(1)?.to_string(),
+                    }),
+                    _ => None,
+                };
+                self.activities.insert(id, activity);
+                event
+            }
+            LogLine::Stop { id } => {
+                let activity = self.activities.remove(&id)?;
+                match activity.activity_type {
+                    ActivityType::Build => Some(NixEvent::BuildFinished {
+                        drv_path: activity.field_str(0)?.into(),
+                    }),
+                    ActivityType::FileTransfer => Some(NixEvent::DownloadFinished {
+                        url: activity.field_str(0)?.to_string(),
+                    }),
+                    _ => None,
+                }
+            }
+            LogLine::Result {
+                id,
+                result_type,
+                fields,
+            } => {
+                let activity = self.activities.get(&id)?;
+                let field_str = |i: usize| fields.get(i).and_then(Value::as_str);
+                let field_u64 = |i: usize| fields.get(i).and_then(Value::as_u64).unwrap_or(0);
+                match result_type {
+                    RESULT_BUILD_LOG_LINE | RESULT_POST_BUILD_LOG_LINE => {
+                        Some(NixEvent::BuildLogLine {
+                            drv_path: activity.field_str(0)?.into(),
+                            line: field_str(0)?.to_string(),
+                        })
+                    }
+                    RESULT_SET_PHASE => Some(NixEvent::BuildPhase {
+                        drv_path: activity.field_str(0)?.into(),
+                        phase: field_str(0)?.to_string(),
+                    }),
+                    RESULT_PROGRESS => Some(NixEvent::Progress {
+                        id,
+                        activity: activity.activity_type,
+                        done: field_u64(0),
+                        expected: field_u64(1),
+                        running: field_u64(2),
+                        failed: field_u64(3),
+                    }),
+                    _ => None,
+                }
+            }
+            LogLine::Msg { level, msg } => match DRV_FAILED.captures(&msg) {
+                Some(captures) if level == 0 => Some(NixEvent::DerivationFailed {
+                    drv_path: captures[1].into(),
+                    message: msg.clone(),
+                }),
+                _ => Some(NixEvent::Message {
+                    level,
+                    message: msg,
+                }),
+            },
+        }
+    }
+}
+
+impl NixCmd {
+    /// Like [NixCmd::run_with], but passes `--log-format internal-json` to Nix,
+    /// calling `on_event` with each [NixEvent] as it happens.
+    ///
+    /// Nix's own progress output is not shown; it is up to `on_event` to report
+    /// progress. Lines of stderr not in the JSON format are passed through.
+    pub async fn run_with_events<F, E>(
+        &self,
+        subcommands: &[&str],
+        f: F,
+        on_event: E,
+    ) -> Result<Vec<u8>, CommandError>
+    where
+        F: FnOnce(&mut Command),
+        E: FnMut(NixEvent),
+    {
+        let mut cmd = self.command(subcommands);
+        cmd.args(["--log-format", "internal-json"]);
+        f(&mut cmd);
+        run_command_with_events(&mut cmd, on_event).await
+    }
+}
+
+/// Run the given [Command] (which must be passed `--log-format internal-json`), calling `on_event` with each [NixEvent].
+///
+/// See [NixCmd::run_with_events].
+pub async fn run_command_with_events<E>(
+    cmd: &mut Command,
+    mut on_event: E,
+) -> Result<Vec<u8>, CommandError>
+where
+    E: FnMut(NixEvent),
+{
+    trace_cmd(cmd);
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    let read_stdout = async {
+        let mut buf = vec![];
+        stdout.read_to_end(&mut buf).await.map(|_| buf)
+    };
+    // Error messages, and lines not in the JSON format, for reporting failures
+    let read_stderr = async {
+        let prefix = log_prefix();
+        let mut parser = EventParser::default();
+        let mut errors = vec![];
+        let mut reader = BufReader::new(stderr);
+        let mut line = vec![];
+        // NOTE: We read bytes (not `String`s) because build logs may contain bad UTF-8.
+        while reader.read_until(b'\n', &mut line).await? > 0 {
+            let s = String::from_utf8_lossy(&line);
+            let s = s.trim_end_matches(['\r', '\n']);
+            match parser.parse_line(s) {
+                ParsedLine::Event(event) => {
+                    if let NixEvent::Message { level: 0, message }
+                    | NixEvent::DerivationFailed { message, .. } = &event
+                    {
+                        errors.push(message.clone());
+                    }
+                    on_event(event);
+                }
+                ParsedLine::Nothing => {}
+                ParsedLine::Raw => {
+                    match &prefix {
+                        Some(prefix) => eprintln!("{} {}", prefix, s),
+                        None => eprintln!("{}", s),
+                    }
+                    errors.push(s.to_string());
+                }
+            }
+            line.clear();
+        }
+        std::io::Result::Ok(errors)
+    };
+
+    let (stdout, errors) = tokio::try_join!(read_stdout, read_stderr)?;
+    let status = child.wait().await?;
+    if status.success() {
+        Ok(stdout)
+    } else {
+        Err(CommandError::ProcessFailed {
+            stderr: errors.join("\n"),
+            exit_code: status.code(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(lines: &[&str]) -> Vec<NixEvent> {
+        let mut parser = EventParser::default();
+        lines
+            .iter()
+            .filter_map(|line| match parser.parse_line(line) {
+                ParsedLine::Event(event) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_events() {
+        let drv = "/nix/store/ab12-hello-2.12.drv";
+        let events = parse_all(&[
+            r#"@nix {"action":"start","id":1,"level":3,"parent":0,"text":"","type":104,"fields":[]}"#,
+            r#"@nix {"action":"start","id":2,"level":3,"parent":1,"text":"building '/nix/store/ab12-hello-2.12.drv'","type":105,"fields":["/nix/store/ab12-hello-2.12.drv","",1,1]}"#,
+            r#"@nix {"action":"result","id":2,"type":104,"fields":["buildPhase"]}"#,
+            r#"@nix {"action":"result","id":2,"type":101,"fields":["make: Nothing to be done"]}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[0,1,1,0]}"#,
+            r#"@nix {"action":"stop","id":2}"#,
+            r#"@nix {"action":"msg","level":0,"msg":"error: builder for '/nix/store/ab12-hello-2.12.drv' failed with exit code 2"}"#,
+            "not a JSON line",
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                NixEvent::BuildStarted {
+                    drv_path: drv.into(),
+                    machine: None
+                },
+                NixEvent::BuildPhase {
+                    drv_path: drv.into(),
+                    phase: "buildPhase".to_string()
+                },
+                NixEvent::BuildLogLine {
+                    drv_path: drv.into(),
+                    line: "make: Nothing to be done".to_string()
+                },
+                NixEvent::Progress {
+                    id: 1,
+                    activity: ActivityType::Builds,
+                    done: 0,
+                    expected: 1,
+                    running: 1,
+                    failed: 0
+                },
+                NixEvent::BuildFinished {
+                    drv_path: drv.into()
+                },
+                NixEvent::DerivationFailed {
+                    drv_path: drv.into(),
+                    message: format!("error: builder for '{}' failed with exit code 2", drv)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_derivation_failed_highlighted() {
+        let events = parse_all(&[
+            r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m Cannot build '\u001b[35;1m/nix/store/ab12-hello.drv\u001b[0m'."}"#,
+        ]);
+        assert!(matches!(
+            &events[..],
+            [NixEvent::DerivationFailed { drv_path, .. }] if drv_path.to_str() == Some("/nix/store/ab12-hello.drv")
+        ));
+    }
+
+    #[test]
+    fn test_failed_derivation() {
+        let stderr = "error: builder for '/nix/store/ab12-hello.drv' failed with exit code 1;\n       last 10 log lines: ...";
+        assert_eq!(
+            failed_derivation(stderr),
+            Some(PathBuf::from("/nix/store/ab12-hello.drv"))
+        );
+        assert_eq!(failed_derivation("error: flake has no outputs"), None);
+    }
+
+    #[test]
+    fn test_non_deterministic_derivation() {
+        let msg = "\u{1b}[31;1merror:\u{1b}[0m derivation '\u{1b}[35;1m/nix/store/ab12-hello.drv\u{1b}[0m' may not be deterministic: output '\u{1b}[35;1m/nix/store/cd34-hello\u{1b}[0m' differs";
+        assert_eq!(
+            non_deterministic_derivation(msg),
+            Some(PathBuf::from("/nix/store/ab12-hello.drv"))
+        );
+        assert_eq!(
+            non_deterministic_derivation("error: builder for '/nix/store/ab12-hello.drv' failed"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_download_events() {
+        let url = "https://cache.nixos.org/nar/0abc.nar.xz";
+        let events = parse_all(&[
+            r#"@nix {"action":"start","id":7,"level":4,"parent":0,"text":"copying path","type":108,"fields":["/nix/store/cd34-glibc","https://cache.nixos.org"]}"#,
+            r#"@nix {"action":"start","id":8,"level":4,"parent":7,"text":"downloading","type":101,"fields":["https://cache.nixos.org/nar/0abc.nar.xz"]}"#,
+            r#"@nix {"action":"result","id":8,"type":105,"fields":[512,1024,0,0]}"#,
+            r#"@nix {"action":"stop","id":8}"#,
+            r#"@nix {"action":"stop","id":7}"#,
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                NixEvent::Substituting {
+                    store_path: "/nix/store/cd34-glibc".into(),
+                    from: "https://cache.nixos.org".to_string()
+                },
+                NixEvent::DownloadStarted {
+                    url: url.to_string()
+                },
+                NixEvent::Progress {
+                    id: 8,
+                    activity: ActivityType::FileTransfer,
+                    done: 512,
+                    expected: 1024,
+                    running: 0,
+                    failed: 0
+                },
+                NixEvent::DownloadFinished {
+                    url: url.to_string()
+                },
+            ]
+        );
+    }
+}
@@ -19,16 +19,19 @@ pub async fn run(
     url: &FlakeUrl,
     args: Vec<String>,
 ) -> Result<(), CommandError> {
-    nixcmd
-        .run_with(&["run"], |cmd| {
-            opts.use_in_command(cmd);
-            cmd.args([url.to_string(), "--".to_string()]);
-            cmd.args(args);
-        })
-        .await?;
+    crate::command::run_command(&mut run_cmd(nixcmd, opts, url, args)).await?;
     Ok(())
 }
 
+/// The [Command] run by [run]
+pub fn run_cmd(nixcmd: &NixCmd, opts: &FlakeOptions, url: &FlakeUrl, args: Vec<String>) -> Command {
+    let mut cmd = nixcmd.command(&["run"]);
+    opts.use_in_command(&mut cmd);
+    cmd.args([url.to_string(), "--".to_string()]);
+    cmd.args(args);
+    cmd
+}
+
 /// Run `nix develop` on the given flake devshell.
 pub async fn develop(
     nixcmd: &NixCmd,
@@ -36,16 +39,24 @@ pub async fn develop(
     url: &FlakeUrl,
     command: NonEmpty<String>,
 ) -> Result<(), CommandError> {
-    nixcmd
-        .run_with(&["develop"], |cmd| {
-            opts.use_in_command(cmd);
-            cmd.args([url.to_string(), "-c".to_string()]);
-            cmd.args(command);
-        })
-        .await?;
+    crate::command::run_command(&mut develop_cmd(nixcmd, opts, url, command)).await?;
     Ok(())
 }
 
+/// The [Command] run by [develop]
+pub fn develop_cmd(
+    nixcmd: &NixCmd,
+    opts: &FlakeOptions,
+    url: &FlakeUrl,
+    command: NonEmpty<String>,
+) -> Command {
+    let mut cmd = nixcmd.command(&["develop"]);
+    opts.use_in_command(&mut cmd);
+    cmd.args([url.to_string(), "-c".to_string()]);
+    cmd.args(command);
+    cmd
+}
+
 /// Run `nix build`
 pub async fn build(
     cmd: &NixCmd,
@@ -62,6 +73,22 @@ pub async fn build(
     Ok(v)
 }
 
+/// Run `nix flake metadata`, returning information about the locked flake
+pub async fn metadata(
+    cmd: &NixCmd,
+    opts: &FlakeOptions,
+    url: &FlakeUrl,
+) -> Result<LockedFlake, NixCmdError> {
+    let stdout: Vec<u8> = cmd
+        .run_with_returning_stdout(&["flake", "metadata"], |c| {
+            opts.use_in_command(c);
+            c.args(["--json", url]);
+        })
+        .await?;
+    let v = serde_json::from_slice::<LockedFlake>(&stdout)?;
+    Ok(v)
+}
+
 /// Run `nix flake lock`
 pub async fn lock(
     cmd: &NixCmd,
@@ -69,25 +96,55 @@ pub async fn lock(
     args: &[&str],
     url: &FlakeUrl,
 ) -> Result<(), NixCmdError> {
-    cmd.run_with(&["flake", "lock"], |c| {
-        c.arg(url.to_string());
-        opts.use_in_command(c);
-        c.args(args);
-    })
-    .await?;
+    crate::command::run_command(&mut lock_cmd(cmd, opts, args, url)).await?;
     Ok(())
 }
 
+/// The [Command] run by [lock]
+pub fn lock_cmd(cmd: &NixCmd, opts: &FlakeOptions, args: &[&str], url: &FlakeUrl) -> Command {
+    let mut c = cmd.command(&["flake", "lock"]);
+    c.arg(url.to_string());
+    opts.use_in_command(&mut c);
+    c.args(args);
+    c
+}
+
 /// Run `nix flake check`
 pub async fn check(cmd: &NixCmd, opts: &FlakeOptions, url: &FlakeUrl) -> Result<(), NixCmdError> {
-    cmd.run_with(&["flake", "check"], |c| {
-        c.arg(url.to_string());
-        opts.use_in_command(c);
-    })
-    .await?;
+    crate::command::run_command(&mut check_cmd(cmd, opts, url)).await?;
     Ok(())
 }
 
+/// The [Command] run by [check]
+pub fn check_cmd(cmd: &NixCmd, opts: &FlakeOptions, url: &FlakeUrl) -> Command {
+    let mut c = cmd.command(&["flake", "check"]);
+    c.arg(url.to_string());
+    opts.use_in_command(&mut c);
+    c
+}
+
+/// A flake, as locked by Nix
+///
+/// Subset of the output of `nix flake metadata --json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedFlake {
+    /// The locked flake URL
+    pub url: FlakeUrl,
+    /// The commit the flake was fetched from (absent if the tree is dirty, or not a repository)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// The commit of a dirty git tree, followed by `-dirty`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dirty_revision: Option<String>,
+    /// Timestamp (seconds since the epoch) of the last modification of the flake
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<u64>,
+    /// Store path of the source tree of the flake
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
 /// A path built by nix, as returned by --print-out-paths
 #[derive(Serialize, Deserialize)]
 pub struct OutPath {
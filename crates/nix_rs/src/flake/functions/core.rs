@@ -10,6 +10,7 @@ use std::{
     path::{Path, PathBuf},
     process::Stdio,
 };
+use tokio::process::Command;
 
 lazy_static! {
     static ref TRUE_FLAKE: FlakeUrl = {
@@ -37,6 +38,48 @@ pub trait FlakeFn {
     /// Initialize the type after reading from Nix build
     fn init(_out: &mut Self::Output) {}
 
+    /// The `nix build` [Command] that [FlakeFn::call] runs for the given arguments.
+    fn command(
+        nixcmd: &NixCmd,
+        impure: bool,
+        pwd: Option<&Path>,
+        m_out_link: Option<&Path>,
+        extra_args: &[String],
+        input: &Self::Input,
+    ) -> Command
+    where
+        Self::Input: Serialize,
+    {
+        let mut cmd = nixcmd.command(&["build"]);
+        cmd.args([Self::flake(), "-L", "--print-out-paths"]);
+
+        if impure {
+            cmd.arg("--impure");
+        }
+
+        if let Some(out_link) = m_out_link {
+            cmd.arg("--out-link");
+            cmd.arg(out_link);
+        } else {
+            cmd.arg("--no-link");
+        }
+
+        let input_vec = to_vec(input);
+        for (k, v) in input_vec {
+            cmd.arg("--override-input");
+            cmd.arg(k);
+            cmd.arg(v);
+        }
+
+        cmd.args(transform_override_inputs(extra_args));
+
+        if let Some(pwd) = pwd {
+            cmd.current_dir(pwd);
+        }
+
+        cmd
+    }
+
     /// Call the flake function, taking `Self::Input`, returning `Self::Output` along with the built store path output as `PathBuf`.
     ///
     /// The store path output can be useful for further processing, if you need it with its entire closure (for e.g., to `nix copy` everything in `Self::Output` at once).
@@ -60,37 +103,12 @@ pub trait FlakeFn {
         Self::Output: Sync + for<'de> Deserialize<'de>,
     {
         async move {
-            let mut cmd = nixcmd.command(&["build"]);
-            cmd.args([Self::flake(), "-L", "--print-out-paths"]);
-
-            if impure {
-                cmd.arg("--impure");
-            }
-
-            if let Some(out_link) = m_out_link {
-                cmd.arg("--out-link");
-                cmd.arg(out_link);
-            } else {
-                cmd.arg("--no-link");
-            }
-
-            let input_vec = to_vec(&input);
-            for (k, v) in input_vec {
-                cmd.arg("--override-input");
-                cmd.arg(k);
-                cmd.arg(v);
-            }
-
-            cmd.args(transform_override_inputs(&extra_args));
-
-            if let Some(pwd) = pwd {
-                cmd.current_dir(pwd);
-            }
+            let mut cmd = Self::command(nixcmd, impure, pwd, m_out_link, &extra_args, &input);
 
             crate::command::trace_cmd(&cmd);
 
-            let output_fut = cmd.stdout(Stdio::piped()).spawn()?;
-            let output = output_fut.wait_with_output().await?;
+            cmd.stdout(Stdio::piped());
+            let output = crate::command::spawn_and_wait_with_output(&mut cmd).await?;
             if output.status.success() {
                 let store_path =
                     PathBuf::from(OsString::from_vec(output.stdout.trim_ascii_end().into()));
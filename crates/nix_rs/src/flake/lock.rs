@@ -0,0 +1,288 @@
+//! Rust module for `flake.lock`
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{command::FlakeOptions, url::FlakeUrl};
+use crate::command::{NixCmd, NixCmdError};
+
+/// The contents of a `flake.lock` file
+///
+/// See [Nix doc](https://nix.dev/manual/nix/2.18/command-ref/new-cli/nix3-flake#lock-files)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlakeLock {
+    /// The locked flakes, keyed by an identifier unique to the lock file (e.g. `nixpkgs_2`)
+    pub nodes: BTreeMap<String, LockNode>,
+    /// Key of the node of the flake itself
+    pub root: String,
+    /// Version of the lock file format
+    pub version: u32,
+    /// The remaining attributes, kept as is so that the lock file can be written back without loss
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// A node of [FlakeLock]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockNode {
+    /// The inputs of this flake
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub inputs: BTreeMap<String, LockInput>,
+    /// The locked reference (absent for the root node)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked: Option<LockedRef>,
+    /// The reference as written in `flake.nix`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<LockedRef>,
+    /// The remaining attributes (e.g. `"flake": false` for non-flake inputs), kept as is so that the node can be written back without loss
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// How an input of a [LockNode] is locked
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LockInput {
+    /// The key of the node locking the input
+    Node(String),
+    /// The input follows the input at this path, from the root (e.g. `["nixpkgs"]`)
+    Follows(Vec<String>),
+}
+
+/// A (locked or original) flake reference in [LockNode]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedRef {
+    /// The type of the reference (e.g. `github`, `path`)
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Timestamp (seconds since the epoch) of the last modification of the locked source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<i64>,
+    /// Hash of the locked source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nar_hash: Option<String>,
+    /// The locked commit, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    /// The remaining attributes (`owner`, `repo`, `path`, `url`, ...), which depend on the type
+    #[serde(flatten)]
+    pub attrs: BTreeMap<String, Value>,
+}
+
+impl FlakeLock {
+    /// Get the lock file of the given flake, using `nix flake metadata`
+    ///
+    /// This works for any flake URL, not only local ones.
+    pub async fn from_nix(
+        cmd: &NixCmd,
+        opts: &FlakeOptions,
+        url: &FlakeUrl,
+    ) -> Result<Self, NixCmdError> {
+        #[derive(Deserialize)]
+        struct Metadata {
+            locks: FlakeLock,
+        }
+        let stdout: Vec<u8> = cmd
+            .run_with_returning_stdout(&["flake", "metadata"], |c| {
+                opts.use_in_command(c);
+                c.args(["--json", url]);
+            })
+            .await?;
+        let v = serde_json::from_slice::<Metadata>(&stdout)?;
+        Ok(v.locks)
+    }
+
+    /// The node of the flake itself
+    pub fn root_node(&self) -> Option<&LockNode> {
+        self.nodes.get(&self.root)
+    }
+
+    /// The key of the node locking the input at the given path from the root (e.g. `["haskell-flake", "nixpkgs"]`), following `follows`
+    pub fn resolve(&self, path: &[String]) -> Option<&str> {
+        let mut key = self.root.as_str();
+        for name in path {
+            key = match self.nodes.get(key)?.inputs.get(name)? {
+                LockInput::Node(key) => key,
+                LockInput::Follows(path) => self.resolve(path)?,
+            };
+        }
+        Some(key)
+    }
+
+    /// The reachable nodes (except the root), each along with the shortest input path that leads to it
+    pub fn input_paths(&self) -> BTreeMap<&str, Vec<String>> {
+        let mut paths: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        let mut queue = std::collections::VecDeque::from([(self.root.as_str(), vec![])]);
+        while let Some((key, path)) = queue.pop_front() {
+            let Some(node) = self.nodes.get(key) else {
+                continue;
+            };
+            for (name, input) in &node.inputs {
+                // Inputs that follow another one do not add any node
+                let LockInput::Node(child) = input else {
+                    continue;
+                };
+                if child == &self.root || paths.contains_key(child.as_str()) {
+                    continue;
+                }
+                let mut child_path = path.clone();
+                child_path.push(name.clone());
+                paths.insert(child, child_path.clone());
+                queue.push_back((child, child_path));
+            }
+        }
+        paths
+    }
+
+    /// A copy of this lock file in which the input `name` of the root is locked as in `other` (along with its own inputs)
+    ///
+    /// Returns `None` if `other` does not lock `name`, or if it only follows another input.
+    pub fn with_input_from(&self, name: &str, other: &FlakeLock) -> Option<FlakeLock> {
+        let LockInput::Node(other_key) = other.root_node()?.inputs.get(name)? else {
+            return None;
+        };
+        let mut res = self.clone();
+        res.nodes.get_mut(&res.root)?.inputs.remove(name);
+        res.remove_unreachable();
+
+        // Copy the nodes from `other`, renaming those whose key is already taken
+        let mut renamed: BTreeMap<String, String> =
+            BTreeMap::from([(other.root.clone(), res.root.clone())]);
+        let mut stack = vec![other_key.clone()];
+        while let Some(key) = stack.pop() {
+            if renamed.contains_key(&key) {
+                continue;
+            }
+            let node = other.nodes.get(&key)?;
+            let new_key = (1..)
+                .map(|n| match n {
+                    1 => key.clone(),
+                    n => format!("{}_{}", key, n),
+                })
+                .find(|k| !res.nodes.contains_key(k))?;
+            for input in node.inputs.values() {
+                if let LockInput::Node(k) = input {
+                    stack.push(k.clone());
+                }
+            }
+            res.nodes.insert(new_key.clone(), node.clone());
+            renamed.insert(key, new_key);
+        }
+        for (key, new_key) in &renamed {
+            if key == &other.root {
+                continue;
+            }
+            for input in res.nodes.get_mut(new_key)?.inputs.values_mut() {
+                if let LockInput::Node(k) = input {
+                    *k = renamed.get(k)?.clone();
+                }
+            }
+        }
+        let root = res.nodes.get_mut(&res.root)?;
+        root.inputs.insert(
+            name.to_string(),
+            LockInput::Node(renamed[other_key].clone()),
+        );
+        Some(res)
+    }
+
+    /// Remove the nodes that are not reachable from the root
+    pub fn remove_unreachable(&mut self) {
+        let reachable: Vec<String> = self.input_paths().into_keys().map(String::from).collect();
+        let root = self.root.clone();
+        self.nodes
+            .retain(|key, _| key == &root || reachable.contains(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flake_lock() {
+        let lock: FlakeLock = serde_json::from_value(serde_json::json!({
+            "nodes": {
+                "haskell-flake": {
+                    "inputs": { "nixpkgs": "nixpkgs_2", "systems": ["systems"] },
+                    "locked": {
+                        "lastModified": 1700000000,
+                        "narHash": "sha256-aaa",
+                        "owner": "srid",
+                        "repo": "haskell-flake",
+                        "rev": "abc",
+                        "type": "github"
+                    },
+                    "original": { "owner": "srid", "repo": "haskell-flake", "type": "github" }
+                },
+                "nixpkgs": {
+                    "locked": { "lastModified": 1700000000, "narHash": "sha256-bbb", "owner": "NixOS", "repo": "nixpkgs", "rev": "def", "type": "github" },
+                    "original": { "owner": "NixOS", "repo": "nixpkgs", "type": "github" }
+                },
+                "nixpkgs_2": {
+                    "locked": { "lastModified": 1600000000, "narHash": "sha256-ccc", "owner": "NixOS", "repo": "nixpkgs", "rev": "ghi", "type": "github" },
+                    "original": { "owner": "NixOS", "repo": "nixpkgs", "type": "github" }
+                },
+                "systems": {
+                    "locked": { "lastModified": 1700000000, "narHash": "sha256-ddd", "path": "/tmp/systems", "type": "path" },
+                    "original": { "path": "/tmp/systems", "type": "path" }
+                },
+                "root": {
+                    "inputs": { "haskell-flake": "haskell-flake", "nixpkgs": "nixpkgs", "systems": "systems" }
+                }
+            },
+            "root": "root",
+            "version": 7
+        }))
+        .unwrap();
+        let path = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            lock.resolve(&path(&["haskell-flake", "nixpkgs"])),
+            Some("nixpkgs_2")
+        );
+        assert_eq!(
+            lock.resolve(&path(&["haskell-flake", "systems"])),
+            Some("systems")
+        );
+        assert_eq!(lock.resolve(&path(&["missing"])), None);
+        let paths = lock.input_paths();
+        assert_eq!(paths["nixpkgs_2"], path(&["haskell-flake", "nixpkgs"]));
+        assert_eq!(paths["systems"], path(&["systems"]));
+        assert_eq!(paths.len(), 4);
+        let nixpkgs = lock.nodes["nixpkgs"].locked.as_ref().unwrap();
+        assert_eq!(nixpkgs.type_, "github");
+        assert_eq!(nixpkgs.attrs["repo"], "nixpkgs");
+
+        // Lock `haskell-flake` (and its nixpkgs) as in another lock file
+        let mut other = lock.clone();
+        other.nodes.get_mut("haskell-flake").unwrap().locked = lock.nodes["nixpkgs"].locked.clone();
+        other.nodes.get_mut("nixpkgs_2").unwrap().locked = None;
+        let mixed = lock.with_input_from("haskell-flake", &other).unwrap();
+        assert_eq!(mixed.nodes.len(), lock.nodes.len());
+        assert_eq!(mixed.nodes["haskell-flake"], other.nodes["haskell-flake"]);
+        assert_eq!(mixed.nodes["nixpkgs_2"], other.nodes["nixpkgs_2"]);
+        assert_eq!(mixed.nodes["nixpkgs"], lock.nodes["nixpkgs"]);
+        assert_eq!(lock.with_input_from("missing", &other), None);
+    }
+
+    #[test]
+    fn test_flake_lock_round_trip() {
+        let json = serde_json::json!({
+            "nodes": {
+                "root": { "inputs": { "systems": "systems" } },
+                "systems": {
+                    "flake": false,
+                    "locked": { "lastModified": 1700000000, "narHash": "sha256-ddd", "path": "/tmp/systems", "type": "path" },
+                    "original": { "path": "/tmp/systems", "type": "path" }
+                }
+            },
+            "root": "root",
+            "version": 7
+        });
+        let lock: FlakeLock = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(lock.nodes["systems"].extra["flake"], false);
+        assert_eq!(serde_json::to_value(&lock).unwrap(), json);
+    }
+}
@@ -3,6 +3,7 @@
 pub mod command;
 pub mod eval;
 pub mod functions;
+pub mod lock;
 pub mod outputs;
 pub mod schema;
 pub mod system;
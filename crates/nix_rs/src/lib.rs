@@ -9,9 +9,12 @@ pub mod config;
 pub mod copy;
 pub mod detsys_installer;
 pub mod env;
+pub mod events;
 pub mod flake;
 pub mod info;
+pub mod path_info;
 pub mod refs;
+pub mod sign;
 pub mod store;
 pub mod system_list;
 pub mod version;
@@ -0,0 +1,91 @@
+//! Rust module for `nix path-info`.
+use std::{collections::BTreeMap, path::PathBuf};
+
+use bytesize::ByteSize;
+use serde::Deserialize;
+
+use crate::{
+    command::{NixCmd, NixCmdError},
+    store::{path::StorePath, uri::StoreURI},
+};
+
+/// Get the closure size of the given store paths, using `nix path-info --closure-size`.
+///
+/// The paths are looked up in `store` if given, otherwise in the local store.
+/// Paths that are not valid in the store are absent from the result.
+pub async fn nix_closure_sizes(
+    cmd: &NixCmd,
+    store: Option<&StoreURI>,
+    paths: &[StorePath],
+) -> Result<BTreeMap<StorePath, ByteSize>, NixCmdError> {
+    if paths.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let stdout = cmd
+        .run_with_returning_stdout(&["path-info"], |c| {
+            c.args(["--json", "--closure-size"]);
+            if let Some(store) = store {
+                store.use_in_command("--store", c);
+            }
+            c.args(paths);
+        })
+        .await?;
+    Ok(parse_path_info_json(&stdout)?)
+}
+
+/// The fields we need from the JSON output of `nix path-info`
+#[derive(Deserialize)]
+struct PathInfo {
+    #[serde(rename = "closureSize")]
+    closure_size: Option<u64>,
+}
+
+/// The JSON output of `nix path-info`, whose format changed in Nix 2.19
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathInfoJson {
+    /// Nix >= 2.19: an object keyed by path (with `null` for invalid paths)
+    ByPath(BTreeMap<PathBuf, Option<PathInfo>>),
+    /// Nix < 2.19: a list of objects having a `path` field
+    List(Vec<PathInfoWithPath>),
+}
+
+#[derive(Deserialize)]
+struct PathInfoWithPath {
+    path: PathBuf,
+    #[serde(flatten)]
+    info: PathInfo,
+}
+
+fn parse_path_info_json(json: &[u8]) -> Result<BTreeMap<StorePath, ByteSize>, serde_json::Error> {
+    let infos: Vec<(PathBuf, Option<PathInfo>)> = match serde_json::from_slice(json)? {
+        PathInfoJson::ByPath(m) => m.into_iter().collect(),
+        PathInfoJson::List(l) => l.into_iter().map(|i| (i.path, Some(i.info))).collect(),
+    };
+    Ok(infos
+        .into_iter()
+        .filter_map(|(path, info)| {
+            let size = info?.closure_size?;
+            Some((StorePath::new(path), ByteSize::b(size)))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_info_json() {
+        let expected = BTreeMap::from([(
+            StorePath::new(PathBuf::from("/nix/store/aaa-hello")),
+            ByteSize::b(1024),
+        )]);
+        // Nix >= 2.19
+        let new = br#"{"/nix/store/aaa-hello": {"closureSize": 1024, "narSize": 512}, "/nix/store/bbb-gone": null}"#;
+        assert_eq!(parse_path_info_json(new).unwrap(), expected);
+        // Nix < 2.19
+        let old = br#"[{"path": "/nix/store/aaa-hello", "closureSize": 1024, "narSize": 512}]"#;
+        assert_eq!(parse_path_info_json(old).unwrap(), expected);
+    }
+}
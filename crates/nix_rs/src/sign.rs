@@ -0,0 +1,27 @@
+//! Rust module for `nix store sign`.
+use crate::command::{CommandError, NixCmd};
+use std::{ffi::OsStr, path::Path};
+
+/// Sign store paths, along with their closure, using `nix store sign`.
+///
+/// # Arguments
+///
+/// * `cmd` - The `nix` command
+/// * `key_file` - File containing the secret key to sign with (see `nix key generate-secret`)
+/// * `paths` - The paths to sign. Limit this to be within the limit of Unix process arguments size limit.
+pub async fn nix_store_sign<I, P>(
+    cmd: &NixCmd,
+    key_file: &Path,
+    paths: I,
+) -> Result<(), CommandError>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path> + AsRef<OsStr>,
+{
+    cmd.run_with(&["store", "sign"], |cmd| {
+        cmd.arg("--recursive").arg("--key-file").arg(key_file);
+        cmd.args(paths);
+    })
+    .await?;
+    Ok(())
+}
@@ -1,34 +1,126 @@
 //! Store URI management
-use std::{fmt, str::FromStr};
+use std::{fmt, path::PathBuf, str::FromStr};
 
 use serde::{Deserialize, Serialize};
+use serde_with::{
+    formats::CommaSeparator, serde_as, DeserializeFromStr, SerializeDisplay, StringWithSeparator,
+};
 use thiserror::Error;
-use url::Url;
+use tokio::process::Command;
+use url::{form_urlencoded, Url};
+
+use crate::flake::system::System;
 
 /// Refers to a Nix store somewhere.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
 pub enum StoreURI {
     /// Nix store accessible over SSH.
     SSH(SSHStoreURI, Opts),
+    /// Binary cache, such as `file:///var/cache/nix`, `https://…` or `s3://…`
+    ///
+    /// Query parameters (e.g. `?compression=zstd`) are passed as is to Nix.
+    BinaryCache(Url),
 }
 
 /// User passed options for a store URI
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Opts {
     /// Whether to copy all flake inputs recursively
     ///
     /// If disabled, we copy only the flake source itself. Enabling this option is useful when there are private Git inputs but the target machine does not have access to them.
-    #[serde(rename = "copy-inputs", default = "bool::default")]
+    #[serde(
+        rename = "copy-inputs",
+        default = "bool::default",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
     pub copy_inputs: bool,
+
+    /// The systems this store builds for, as a comma-separated list (e.g. `?systems=aarch64-darwin,x86_64-darwin`)
+    ///
+    /// An empty list means that the systems are not restricted.
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, System>")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub systems: Vec<System>,
 }
 
 /// Remote SSH store URI
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+///
+/// For example, `ssh-ng://admin@builder:2222?ssh-key=/etc/nix/id_builder&ssh-option=ProxyJump=bastion`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SSHStoreURI {
+    /// Whether to use the `ssh-ng://` protocol (rather than the legacy `ssh://` one)
+    pub ng: bool,
     /// SSH user
     pub user: Option<String>,
     /// SSH host
     pub host: String,
+    /// SSH port, if not the default one
+    pub port: Option<u16>,
+    /// Identity file to authenticate with (`?ssh-key=`, as understood by Nix)
+    pub ssh_key: Option<PathBuf>,
+    /// Options passed to `ssh -o` (`?ssh-option=`, which may be repeated), such as `ProxyJump=bastion`
+    pub ssh_options: Vec<String>,
+}
+
+impl SSHStoreURI {
+    /// The URI scheme
+    pub fn scheme(&self) -> &'static str {
+        if self.ng {
+            "ssh-ng"
+        } else {
+            "ssh"
+        }
+    }
+
+    /// The `ssh` options (preceding the destination) to connect to this host
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(key) = &self.ssh_key {
+            args.extend(["-i".to_string(), key.to_string_lossy().to_string()]);
+        }
+        for opt in &self.ssh_options {
+            args.extend(["-o".to_string(), opt.clone()]);
+        }
+        args
+    }
+
+    /// The store URI to pass to Nix
+    ///
+    /// Nix does not understand our options, nor (in older versions) ports; those are passed through `NIX_SSHOPTS` instead (see [StoreURI::use_in_command]).
+    fn nix_store_uri(&self) -> String {
+        let mut uri = format!("{}://{}", self.scheme(), self);
+        if let Some(key) = &self.ssh_key {
+            let query = form_urlencoded::Serializer::new(String::new())
+                .append_pair("ssh-key", &key.to_string_lossy())
+                .finish();
+            uri = format!("{}?{}", uri, query);
+        }
+        uri
+    }
+
+    /// The value of `NIX_SSHOPTS` for Nix to connect to this host, given its current value
+    fn nix_sshopts(&self, current: Option<String>) -> Option<String> {
+        let mut args = vec![];
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        for opt in &self.ssh_options {
+            args.extend(["-o".to_string(), opt.clone()]);
+        }
+        if args.is_empty() {
+            return current;
+        }
+        // Older versions of Nix split `NIX_SSHOPTS` on whitespace, without handling quotes
+        let opts = args.join(" ");
+        Some(match current {
+            Some(current) if !current.trim().is_empty() => format!("{} {}", current, opts),
+            _ => opts,
+        })
+    }
 }
 
 /// Error parsing a store URI
@@ -43,6 +135,9 @@ pub enum StoreURIParseError {
     /// Missing host
     #[error("Missing host")]
     MissingHost,
+    /// An `ssh-option` containing whitespace, which cannot be passed through `NIX_SSHOPTS`
+    #[error("ssh-option cannot contain whitespace, as Nix splits NIX_SSHOPTS on it: {0:?}")]
+    SSHOptionWithWhitespace(String),
     /// Query string parse error
     #[error(transparent)]
     QueryParseError(#[from] serde_qs::Error),
@@ -51,11 +146,11 @@ pub enum StoreURIParseError {
 impl StoreURI {
     /// Parse a Nix store URI
     ///
-    /// Currently only supports `ssh` scheme
+    /// Currently only supports the `ssh` and `ssh-ng` schemes, and binary caches (`file`, `http`, `https` and `s3` schemes)
     pub fn parse(uri: &str) -> Result<Self, StoreURIParseError> {
         let url = Url::parse(uri)?;
         match url.scheme() {
-            "ssh" => {
+            "ssh" | "ssh-ng" => {
                 let host = url
                     .host_str()
                     .ok_or(StoreURIParseError::MissingHost)?
@@ -65,11 +160,36 @@ impl StoreURI {
                 } else {
                     None
                 };
-                let opts = serde_qs::from_str(url.query().unwrap_or(""))?;
-                let ssh_uri = SSHStoreURI { user, host };
+                let mut ssh_uri = SSHStoreURI {
+                    ng: url.scheme() == "ssh-ng",
+                    user,
+                    host,
+                    port: url.port(),
+                    ..Default::default()
+                };
+                // The SSH parameters go to `ssh_uri`, the rest to `Opts`
+                let mut query = form_urlencoded::Serializer::new(String::new());
+                for (key, value) in url.query_pairs() {
+                    match key.as_ref() {
+                        "ssh-key" => ssh_uri.ssh_key = Some(PathBuf::from(value.as_ref())),
+                        "ssh-option" => {
+                            if value.contains(char::is_whitespace) {
+                                return Err(StoreURIParseError::SSHOptionWithWhitespace(
+                                    value.to_string(),
+                                ));
+                            }
+                            ssh_uri.ssh_options.push(value.to_string())
+                        }
+                        _ => {
+                            query.append_pair(&key, &value);
+                        }
+                    }
+                }
+                let opts = serde_qs::from_str(&query.finish())?;
                 let store_uri = StoreURI::SSH(ssh_uri, opts);
                 Ok(store_uri)
             }
+            "file" | "http" | "https" | "s3" => Ok(StoreURI::BinaryCache(url)),
             // Add future schemes here
             scheme => Err(StoreURIParseError::UnsupportedScheme(scheme.to_string())),
         }
@@ -77,8 +197,30 @@ impl StoreURI {
 
     /// Get the options for this store URI
     pub fn get_options(&self) -> &Opts {
+        static DEFAULT_OPTS: Opts = Opts {
+            copy_inputs: false,
+            systems: Vec::new(),
+        };
         match self {
             StoreURI::SSH(_, opts) => opts,
+            StoreURI::BinaryCache(_) => &DEFAULT_OPTS,
+        }
+    }
+
+    /// Pass this store to the given Nix command, as the value of `flag` (e.g. `--to`)
+    ///
+    /// For SSH stores, the port and SSH options are passed through the `NIX_SSHOPTS` environment variable.
+    pub fn use_in_command(&self, flag: &str, cmd: &mut Command) {
+        match self {
+            StoreURI::SSH(uri, _opts) => {
+                cmd.arg(flag).arg(uri.nix_store_uri());
+                if let Some(sshopts) = uri.nix_sshopts(std::env::var("NIX_SSHOPTS").ok()) {
+                    cmd.env("NIX_SSHOPTS", sshopts);
+                }
+            }
+            StoreURI::BinaryCache(url) => {
+                cmd.arg(flag).arg(url.as_str());
+            }
         }
     }
 }
@@ -91,6 +233,7 @@ impl FromStr for StoreURI {
     }
 }
 
+/// The SSH destination (`[user@]host`)
 impl fmt::Display for SSHStoreURI {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(user) = &self.user {
@@ -103,10 +246,108 @@ impl fmt::Display for SSHStoreURI {
 impl fmt::Display for StoreURI {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            StoreURI::SSH(uri, _opts) => {
-                // This should construct a valid store URI.
-                write!(f, "ssh://{}", uri)
+            StoreURI::SSH(uri, opts) => {
+                // This should construct a valid store URI, which parses back to `self`.
+                write!(f, "{}://{}", uri.scheme(), uri)?;
+                if let Some(port) = uri.port {
+                    write!(f, ":{}", port)?;
+                }
+                let mut query = form_urlencoded::Serializer::new(String::new());
+                if opts.copy_inputs {
+                    query.append_pair("copy-inputs", "true");
+                }
+                if !opts.systems.is_empty() {
+                    let systems: Vec<&str> = opts.systems.iter().map(|s| s.as_ref()).collect();
+                    query.append_pair("systems", &systems.join(","));
+                }
+                if let Some(key) = &uri.ssh_key {
+                    query.append_pair("ssh-key", &key.to_string_lossy());
+                }
+                for opt in &uri.ssh_options {
+                    query.append_pair("ssh-option", opt);
+                }
+                let query = query.finish();
+                if !query.is_empty() {
+                    write!(f, "?{}", query)?;
+                }
+                Ok(())
             }
+            StoreURI::BinaryCache(url) => write!(f, "{}", url),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_binary_cache() {
+        let uri = StoreURI::parse("file:///tmp/cache?compression=zstd").unwrap();
+        assert!(matches!(uri, StoreURI::BinaryCache(_)));
+        assert_eq!(uri.to_string(), "file:///tmp/cache?compression=zstd");
+        assert!(StoreURI::parse("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_ssh_systems() {
+        let uri =
+            StoreURI::parse("ssh://admin@mac-mini?systems=aarch64-darwin,x86_64-darwin").unwrap();
+        let opts = uri.get_options();
+        assert_eq!(
+            opts.systems,
+            vec![
+                System::from("aarch64-darwin"),
+                System::from("x86_64-darwin")
+            ]
+        );
+        assert!(!opts.copy_inputs);
+        let uri = StoreURI::parse("ssh://mac-mini").unwrap();
+        assert!(uri.get_options().systems.is_empty());
+    }
+
+    #[test]
+    fn test_parse_ssh_connection() {
+        let s = "ssh-ng://admin@builder:2222?copy-inputs=true&systems=x86_64-linux&ssh-key=%2Fetc%2Fnix%2Fid_builder&ssh-option=ProxyJump%3Dbastion&ssh-option=ConnectTimeout%3D5";
+        let uri = StoreURI::parse(s).unwrap();
+        let StoreURI::SSH(ssh_uri, opts) = &uri else {
+            panic!("not an SSH store: {:?}", uri);
+        };
+        assert!(ssh_uri.ng);
+        assert_eq!(ssh_uri.to_string(), "admin@builder");
+        assert_eq!(ssh_uri.port, Some(2222));
+        assert!(opts.copy_inputs);
+        assert_eq!(opts.systems, vec![System::from("x86_64-linux")]);
+        assert_eq!(
+            ssh_uri.ssh_args(),
+            vec![
+                "-p",
+                "2222",
+                "-i",
+                "/etc/nix/id_builder",
+                "-o",
+                "ProxyJump=bastion",
+                "-o",
+                "ConnectTimeout=5"
+            ]
+        );
+        assert_eq!(
+            ssh_uri.nix_store_uri(),
+            "ssh-ng://admin@builder?ssh-key=%2Fetc%2Fnix%2Fid_builder"
+        );
+        assert_eq!(
+            ssh_uri.nix_sshopts(Some("-v".to_string())).unwrap(),
+            "-v -p 2222 -o ProxyJump=bastion -o ConnectTimeout=5"
+        );
+        // `Display` round-trips
+        assert_eq!(uri.to_string(), s);
+        assert_eq!(StoreURI::parse(&uri.to_string()).unwrap(), uri);
+        let plain = StoreURI::parse("ssh://builder").unwrap();
+        assert_eq!(plain.to_string(), "ssh://builder");
+        assert_eq!(StoreURI::parse(&plain.to_string()).unwrap(), plain);
+        assert!(matches!(
+            StoreURI::parse("ssh://builder?ssh-option=ProxyCommand%3Dssh%20-W%20%25h%3A%25p%20bastion"),
+            Err(StoreURIParseError::SSHOptionWithWhitespace(opt)) if opt == "ProxyCommand=ssh -W %h:%p bastion"
+        ));
+    }
+}
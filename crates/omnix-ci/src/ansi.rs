@@ -0,0 +1,32 @@
+//! ANSI escape sequences, as found in Nix's error messages
+
+/// Remove ANSI escape sequences (e.g. colors) from the given string
+///
+/// For rendering Nix's error messages outside of a terminal, such as in reports.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip until the final byte of the escape sequence, e.g. the 'm' in "\x1b[31m"
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("\x1b[31;1merror:\x1b[0m boom"), "error: boom");
+    }
+}
@@ -0,0 +1,223 @@
+//! Determine which subflakes are affected by changes since a git ref (`om ci run --since`)
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use nix_rs::flake::url::FlakeUrl;
+use tokio::process::Command;
+
+use crate::config::subflake::SubflakeConfig;
+
+/// Files changed since a git ref, relative to the root of a (local) flake
+#[derive(Debug, Clone)]
+pub struct ChangedFiles {
+    /// The git ref the changes are relative to
+    pub since: String,
+    /// Root of the flake
+    pub root: PathBuf,
+    /// Changed files, relative to [ChangedFiles::root]
+    pub files: Vec<PathBuf>,
+}
+
+impl ChangedFiles {
+    /// Get the files changed in the working tree of the given flake since `git_ref`.
+    ///
+    /// Includes uncommitted and untracked (but not ignored) files.
+    pub async fn since(url: &FlakeUrl, git_ref: &str) -> Result<Self> {
+        let root = url
+            .as_local_path()
+            .with_context(|| format!("--since requires a local flake, but {} is not", url))?
+            .to_path_buf();
+        let mut files = git_lines(&root, &["diff", "--name-only", "--relative", git_ref])
+            .await
+            .with_context(|| format!("Unable to diff against {}", git_ref))?;
+        files.extend(git_lines(&root, &["ls-files", "--others", "--exclude-standard"]).await?);
+        Ok(ChangedFiles {
+            since: git_ref.to_string(),
+            root,
+            files: files.into_iter().map(PathBuf::from).collect(),
+        })
+    }
+
+    /// Whether any of the changed files can affect the given subflake.
+    ///
+    /// A subflake is affected by changes to its directory, to any local path it
+    /// uses as a flake input (per its `flake.lock` or `overrideInputs`), and to
+    /// the CI configuration (see [CONFIG_FILES]).
+    pub fn affects(&self, subflake: &SubflakeConfig) -> bool {
+        let watched = self.watched_paths(subflake);
+        self.files.iter().any(|file| {
+            CONFIG_FILES.iter().any(|f| file == Path::new(f))
+                || watched.iter().any(|dir| file.starts_with(dir))
+        })
+    }
+
+    /// Paths, relative to [ChangedFiles::root], whose changes affect the given subflake
+    ///
+    /// Relative paths of inputs, like those of `path` inputs in `flake.lock`, are relative to the subflake directory.
+    fn watched_paths(&self, subflake: &SubflakeConfig) -> Vec<PathBuf> {
+        let dir = normalize(Path::new(&subflake.dir));
+        let mut paths = vec![dir.clone()];
+        for url in subflake.override_inputs.values() {
+            if let Some(path) = url.as_local_path() {
+                paths.extend(self.relative_to_root(&dir, path));
+            }
+        }
+        for path in flake_lock_path_inputs(&self.root.join(&dir)) {
+            paths.extend(self.relative_to_root(&dir, &path));
+        }
+        paths
+    }
+
+    /// Resolve `path` (relative to `base`, itself relative to the root) into a path relative to the root.
+    ///
+    /// Returns `None` for paths outside of the root, since we cannot know their changes.
+    fn relative_to_root(&self, base: &Path, path: &Path) -> Option<PathBuf> {
+        let path = if path.is_absolute() {
+            let root = self.root.canonicalize().ok()?;
+            path.strip_prefix(root).ok()?.to_path_buf()
+        } else {
+            normalize(&base.join(path))
+        };
+        match path.components().next() {
+            Some(Component::ParentDir) => None,
+            _ => Some(path),
+        }
+    }
+}
+
+/// Files (relative to the root of the flake) that may define the CI configuration, and thus affect every subflake
+///
+/// The configuration is read from `om.yaml`, or else from the `om` output of `flake.nix`.
+const CONFIG_FILES: &[&str] = &["om.yaml", "flake.nix"];
+
+/// Paths of the `path` inputs in the `flake.lock` of the flake in `dir`, if any
+fn flake_lock_path_inputs(dir: &Path) -> Vec<PathBuf> {
+    let Ok(s) = std::fs::read_to_string(dir.join("flake.lock")) else {
+        return vec![];
+    };
+    let Ok(lock) = serde_json::from_str::<serde_json::Value>(&s) else {
+        tracing::warn!(
+            "Unable to parse {:?}; ignoring its inputs",
+            dir.join("flake.lock")
+        );
+        return vec![];
+    };
+    let nodes = lock["nodes"]
+        .as_object()
+        .into_iter()
+        .flat_map(|m| m.values());
+    nodes
+        .flat_map(|node| [&node["locked"], &node["original"]])
+        .filter(|input| input["type"] == "path")
+        .filter_map(|input| input["path"].as_str().map(PathBuf::from))
+        .collect()
+}
+
+/// Lexically normalize a relative path, dropping `.` and resolving `..` where possible
+fn normalize(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(res.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                res.pop();
+            }
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Run git in `dir`, returning the lines of its output
+async fn git_lines(dir: &Path, args: &[&str]) -> Result<Vec<String>> {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(dir).args(args);
+    let stdout = match nix_rs::command::run_command(&mut cmd).await {
+        Ok(stdout) => stdout,
+        Err(err) => bail!("git {} failed: {}", args.join(" "), err),
+    };
+    Ok(String::from_utf8_lossy(&stdout)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn subflake(dir: &str, override_inputs: &[(&str, &str)]) -> SubflakeConfig {
+        SubflakeConfig {
+            dir: dir.to_string(),
+            override_inputs: override_inputs
+                .iter()
+                .map(|(k, v)| (k.to_string(), FlakeUrl(v.to_string())))
+                .collect::<BTreeMap<_, _>>(),
+            ..SubflakeConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_affects() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("doc")).unwrap();
+        std::fs::write(
+            root.path().join("doc/flake.lock"),
+            serde_json::json!({
+                "nodes": {
+                    "lib": {
+                        "locked": { "type": "path", "path": "../lib" },
+                        "original": { "type": "path", "path": "../lib" }
+                    },
+                    "root": { "inputs": { "lib": "lib" } }
+                },
+                "root": "root",
+                "version": 7
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let changes = |files: &[&str]| ChangedFiles {
+            since: "main".to_string(),
+            root: root.path().to_path_buf(),
+            files: files.iter().map(PathBuf::from).collect(),
+        };
+
+        let root_flake = subflake(".", &[]);
+        let doc = subflake("doc", &[]);
+        let test = subflake(
+            "./test",
+            &[("omnix", "../.."), ("local", "path:../lib/foo")],
+        );
+
+        assert!(changes(&["README.md"]).affects(&root_flake));
+        assert!(!changes(&["README.md"]).affects(&doc));
+        assert!(changes(&["doc/index.md"]).affects(&doc));
+        assert!(!changes(&["docs/index.md"]).affects(&doc));
+        // Path input in doc/flake.lock
+        assert!(changes(&["lib/default.nix"]).affects(&doc));
+        // Override inputs
+        assert!(changes(&["lib/foo/default.nix"]).affects(&test));
+        assert!(!changes(&["lib/bar/default.nix"]).affects(&test));
+        // The CI configuration affects everything
+        assert!(changes(&["om.yaml"]).affects(&doc));
+        assert!(changes(&["flake.nix"]).affects(&test));
+        assert!(!changes(&["doc/flake.nix"]).affects(&test));
+        assert!(!changes(&[]).affects(&root_flake));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("./foo/../bar/.")), PathBuf::from("bar"));
+        assert_eq!(
+            normalize(Path::new("foo/../../bar")),
+            PathBuf::from("../bar")
+        );
+        assert_eq!(normalize(Path::new(".")), PathBuf::new());
+    }
+}
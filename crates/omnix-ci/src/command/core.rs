@@ -7,7 +7,14 @@ use tracing::instrument;
 
 use crate::flake_ref::FlakeRef;
 
-use super::{gh_matrix::GHMatrixCommand, run::RunCommand};
+use super::{
+    diff::DiffCommand,
+    gh_matrix::GHMatrixCommand,
+    pipeline::{PipelineCommand, Provider},
+    plan::PlanCommand,
+    run::RunCommand,
+    update_lock::UpdateLockCommand,
+};
 
 /// Top-level commands for `om ci`
 #[derive(Debug, Subcommand, Clone)]
@@ -15,9 +22,26 @@ pub enum Command {
     /// Run all CI steps for all or given subflakes
     Run(RunCommand),
 
+    /// Print what `om ci run` would do, without building anything
+    Plan(PlanCommand),
+
     /// Print the Github Actions matrix configuration as JSON
     #[clap(name = "gh-matrix")]
     DumpGithubActionsMatrix(GHMatrixCommand),
+
+    /// Print a GitLab CI child pipeline as YAML
+    GitlabPipeline(PipelineCommand),
+
+    /// Print a Buildkite pipeline as YAML
+    BuildkitePipeline(PipelineCommand),
+
+    /// Compare the outputs of two `om ci run` results JSON
+    Diff(DiffCommand),
+
+    /// Update the flake inputs of all subflakes, run CI, and report the updates as Markdown
+    ///
+    /// If CI fails, the updates are bisected to find the one that breaks it.
+    UpdateLock(Box<UpdateLockCommand>),
 }
 
 impl Default for Command {
@@ -30,21 +54,35 @@ impl Command {
     /// Run the command
     #[instrument(name = "run", skip(self))]
     pub async fn run(self) -> anyhow::Result<()> {
+        if let Command::Diff(cmd) = &self {
+            // Unlike the other subcommands, this does not operate on a flake
+            return cmd.run().await;
+        }
+
         tracing::info!("{}", "\n👟 Reading om.ci config from flake".bold());
         let url = self.get_flake_ref().to_flake_url().await?;
         let cfg = OmConfig::get(self.nixcmd(), &url).await?;
 
         tracing::debug!("OmConfig: {cfg:?}");
         match self {
-            Command::Run(cmd) => cmd.run(cfg).await,
+            Command::Run(cmd) => cmd.run(&cfg).await,
+            Command::Plan(cmd) => cmd.run(cfg).await,
             Command::DumpGithubActionsMatrix(cmd) => cmd.run(cfg).await,
+            Command::GitlabPipeline(cmd) => cmd.run(cfg, Provider::GitLab).await,
+            Command::BuildkitePipeline(cmd) => cmd.run(cfg, Provider::Buildkite).await,
+            Command::UpdateLock(cmd) => cmd.run(cfg).await,
+            Command::Diff(_) => unreachable!("handled above"),
         }
     }
 
     fn nixcmd(&self) -> &NixCmd {
         match self {
             Command::Run(cmd) => &cmd.nixcmd,
+            Command::Plan(cmd) => &cmd.run_cmd.nixcmd,
             Command::DumpGithubActionsMatrix(cmd) => &cmd.nixcmd,
+            Command::GitlabPipeline(cmd) | Command::BuildkitePipeline(cmd) => &cmd.nixcmd,
+            Command::Diff(cmd) => &cmd.nixcmd,
+            Command::UpdateLock(cmd) => &cmd.run_cmd.nixcmd,
         }
     }
 
@@ -52,21 +90,11 @@ impl Command {
     fn get_flake_ref(&self) -> &FlakeRef {
         match self {
             Command::Run(cmd) => &cmd.flake_ref,
+            Command::Plan(cmd) => &cmd.run_cmd.flake_ref,
             Command::DumpGithubActionsMatrix(cmd) => &cmd.flake_ref,
+            Command::GitlabPipeline(cmd) | Command::BuildkitePipeline(cmd) => &cmd.flake_ref,
+            Command::UpdateLock(cmd) => &cmd.run_cmd.flake_ref,
+            Command::Diff(_) => unreachable!("`om ci diff` does not take a flake"),
         }
     }
-
-    /// Convert this type back to the user-facing command line arguments
-    pub fn to_cli_args(&self) -> Vec<String> {
-        let mut args = vec!["ci".to_string(), "run".to_string()];
-        match self {
-            Command::Run(cmd) => {
-                args.extend(cmd.to_cli_args());
-            }
-            Command::DumpGithubActionsMatrix(_cmd) => {
-                unimplemented!("Command::DumpGithubActionsMatrix::to_cli_args")
-            }
-        }
-        args
-    }
 }
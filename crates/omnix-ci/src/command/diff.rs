@@ -0,0 +1,331 @@
+//! The diff command
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bytesize::ByteSize;
+use clap::Parser;
+use colored::Colorize;
+use nix_rs::{
+    command::NixCmd,
+    path_info::nix_closure_sizes,
+    store::{path::StorePath, uri::StoreURI},
+};
+use serde::Serialize;
+
+use super::run::RunResult;
+
+/// Command to compare the outputs of two `om ci run` results
+#[derive(Parser, Debug, Clone)]
+pub struct DiffCommand {
+    /// The old results JSON (e.g. from the base branch)
+    pub old: PathBuf,
+
+    /// The new results JSON (e.g. from a pull request)
+    pub new: PathBuf,
+
+    /// Store to query closure sizes from (defaults to the local store)
+    ///
+    /// Useful when the built paths are not in the local store, but were pushed to a binary cache.
+    #[arg(long, value_name = "STORE_URI")]
+    pub store: Option<StoreURI>,
+
+    /// Print the diff as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// Nix command global options
+    #[command(flatten)]
+    pub nixcmd: NixCmd,
+}
+
+impl DiffCommand {
+    /// Run the command
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let old = read_run_result(&self.old)?;
+        let new = read_run_result(&self.new)?;
+        let mut diff = ResultDiff::new(&old, &new);
+        diff.add_closure_sizes(&self.nixcmd, self.store.as_ref())
+            .await;
+        if self.json {
+            println!("{}", serde_json::to_string(&diff)?);
+        } else {
+            diff.print();
+        }
+        Ok(())
+    }
+}
+
+fn read_run_result(path: &Path) -> anyhow::Result<RunResult> {
+    let file = std::fs::File::open(path).with_context(|| format!("Unable to open {:?}", path))?;
+    serde_json::from_reader(file).with_context(|| format!("Unable to parse results in {:?}", path))
+}
+
+/// Difference between the (named) outputs of two `om ci run` results
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResultDiff {
+    /// Outputs only in the new result
+    pub added: Vec<Output>,
+    /// Outputs only in the old result
+    pub removed: Vec<Output>,
+    /// Outputs whose store path changed
+    pub changed: Vec<ChangedOutput>,
+    /// Number of outputs whose store path did not change
+    pub unchanged: usize,
+}
+
+/// An output, as found in `byName` of the build step's result
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Output {
+    /// The subflake that built it
+    pub subflake: String,
+    /// Name (or pname) of the output
+    pub name: String,
+    /// Store path of the output
+    pub path: StorePath,
+}
+
+/// An output whose store path changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChangedOutput {
+    /// The subflake that built it
+    pub subflake: String,
+    /// Name (or pname) of the output
+    pub name: String,
+    /// Store path in the old result
+    pub old: StorePath,
+    /// Store path in the new result
+    pub new: StorePath,
+    /// Closure sizes, if both paths could be queried
+    #[serde(rename = "closureSize", skip_serializing_if = "Option::is_none")]
+    pub closure_size: Option<SizeChange>,
+}
+
+/// Change in closure size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SizeChange {
+    /// Closure size of the old path, in bytes
+    pub old: u64,
+    /// Closure size of the new path, in bytes
+    pub new: u64,
+}
+
+impl SizeChange {
+    /// The change in bytes (negative if the closure shrank)
+    pub fn delta(&self) -> i64 {
+        self.new as i64 - self.old as i64
+    }
+}
+
+/// Outputs of all subflakes, keyed by (subflake, name)
+fn outputs_by_name(res: &RunResult) -> BTreeMap<(&String, &String), &StorePath> {
+    res.result
+        .iter()
+        .filter_map(|(subflake, steps_res)| {
+            let build = steps_res.build_step.as_ref()?;
+            Some(
+                build
+                    .devour_flake_output
+                    .by_name
+                    .iter()
+                    .map(move |(name, path)| ((subflake, name), path)),
+            )
+        })
+        .flatten()
+        .collect()
+}
+
+impl ResultDiff {
+    /// Compare the outputs of `old` and `new`
+    pub fn new(old: &RunResult, new: &RunResult) -> Self {
+        let old = outputs_by_name(old);
+        let new = outputs_by_name(new);
+        let mut diff = ResultDiff::default();
+        for (&(subflake, name), &new_path) in &new {
+            match old.get(&(subflake, name)) {
+                None => diff.added.push(Output {
+                    subflake: subflake.clone(),
+                    name: name.clone(),
+                    path: new_path.clone(),
+                }),
+                Some(&old_path) if old_path == new_path => diff.unchanged += 1,
+                Some(&old_path) => diff.changed.push(ChangedOutput {
+                    subflake: subflake.clone(),
+                    name: name.clone(),
+                    old: old_path.clone(),
+                    new: new_path.clone(),
+                    closure_size: None,
+                }),
+            }
+        }
+        for (&(subflake, name), &old_path) in &old {
+            if !new.contains_key(&(subflake, name)) {
+                diff.removed.push(Output {
+                    subflake: subflake.clone(),
+                    name: name.clone(),
+                    path: old_path.clone(),
+                });
+            }
+        }
+        diff
+    }
+
+    /// Query the closure sizes of the changed outputs from `store`.
+    ///
+    /// Failures are not fatal (the paths may have been garbage collected, for instance); the sizes are left out instead.
+    pub async fn add_closure_sizes(&mut self, cmd: &NixCmd, store: Option<&StoreURI>) {
+        let paths: Vec<StorePath> = self
+            .changed
+            .iter()
+            .flat_map(|c| [c.old.clone(), c.new.clone()])
+            .collect();
+        let sizes = match nix_closure_sizes(cmd, store, &paths).await {
+            Ok(sizes) => sizes,
+            Err(err) => {
+                tracing::warn!("Unable to query closure sizes: {}", err);
+                return;
+            }
+        };
+        for changed in &mut self.changed {
+            if let (Some(old), Some(new)) = (sizes.get(&changed.old), sizes.get(&changed.new)) {
+                changed.closure_size = Some(SizeChange {
+                    old: old.as_u64(),
+                    new: new.as_u64(),
+                });
+            }
+        }
+    }
+
+    /// Total change in closure size of the changed outputs (whose sizes are known)
+    pub fn total_size_delta(&self) -> i64 {
+        self.changed
+            .iter()
+            .filter_map(|c| c.closure_size.map(|s| s.delta()))
+            .sum()
+    }
+
+    /// Print the diff in a human-readable form
+    pub fn print(&self) {
+        let output_name = |subflake: &str, name: &str| format!("{}/{}", subflake.italic(), name);
+        if !self.added.is_empty() {
+            println!("{}", format!("➕ Added ({})", self.added.len()).bold());
+            for o in &self.added {
+                println!(
+                    "   {} {}",
+                    output_name(&o.subflake, &o.name),
+                    o.path.to_string().dimmed()
+                );
+            }
+        }
+        if !self.removed.is_empty() {
+            println!("{}", format!("➖ Removed ({})", self.removed.len()).bold());
+            for o in &self.removed {
+                println!(
+                    "   {} {}",
+                    output_name(&o.subflake, &o.name),
+                    o.path.to_string().dimmed()
+                );
+            }
+        }
+        if !self.changed.is_empty() {
+            println!("{}", format!("🔁 Changed ({})", self.changed.len()).bold());
+            for c in &self.changed {
+                let size = c
+                    .closure_size
+                    .map(|s| {
+                        format!(
+                            " ({} → {}, {})",
+                            ByteSize::b(s.old),
+                            ByteSize::b(s.new),
+                            format_delta(s.delta())
+                        )
+                    })
+                    .unwrap_or_default();
+                println!("   {}{}", output_name(&c.subflake, &c.name), size);
+            }
+        }
+        println!(
+            "\n📊 {} added, {} removed, {} changed, {} unchanged; closure size change: {}",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            self.unchanged,
+            format_delta(self.total_size_delta())
+        );
+    }
+}
+
+/// Format a change in bytes, such as `+80.0 MB` (in red) or `-1.2 KB` (in green)
+fn format_delta(delta: i64) -> String {
+    let size = ByteSize::b(delta.unsigned_abs());
+    match delta {
+        0 => "±0 B".to_string(),
+        d if d > 0 => format!("+{}", size).red().to_string(),
+        _ => format!("-{}", size).green().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix_rs::flake::url::FlakeUrl;
+
+    use crate::{
+        nix::devour_flake::DevourFlakeOutput,
+        step::{build::BuildStepResult, core::StepsResult},
+    };
+
+    use super::*;
+
+    fn run_result(by_name: &[(&str, &str)]) -> RunResult {
+        let by_name = by_name
+            .iter()
+            .map(|(name, path)| (name.to_string(), StorePath::new(PathBuf::from(path))))
+            .collect();
+        let steps_res = StepsResult {
+            build_step: Some(BuildStepResult {
+                devour_flake_output: DevourFlakeOutput {
+                    out_paths: vec![],
+                    by_name,
+                },
+                all_deps: None,
+                filtered_attrs: vec![],
+                non_reproducible: None,
+            }),
+            ..Default::default()
+        };
+        RunResult {
+            systems: vec![],
+            flake: FlakeUrl(".".to_string()),
+            result: BTreeMap::from([("ROOT".to_string(), steps_res)]),
+            skipped: BTreeMap::new(),
+            metadata: None,
+            runs: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_result_diff() {
+        let old = run_result(&[
+            ("foo", "/nix/store/aaa-foo"),
+            ("bar", "/nix/store/bbb-bar"),
+            ("gone", "/nix/store/ccc-gone"),
+        ]);
+        let new = run_result(&[
+            ("foo", "/nix/store/aaa-foo"),
+            ("bar", "/nix/store/ddd-bar"),
+            ("new", "/nix/store/eee-new"),
+        ]);
+        let diff = ResultDiff::new(&old, &new);
+        let names = |outputs: &[Output]| outputs.iter().map(|o| o.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&diff.added), vec!["new"]);
+        assert_eq!(names(&diff.removed), vec!["gone"]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].name, "bar");
+        assert_eq!(diff.unchanged, 1);
+
+        let size = SizeChange { old: 100, new: 40 };
+        assert_eq!(size.delta(), -60);
+    }
+}
@@ -3,7 +3,9 @@ use clap::Parser;
 use nix_rs::{command::NixCmd, flake::system::System};
 use omnix_common::config::OmConfig;
 
-use crate::{config::subflakes::SubflakesConfig, flake_ref::FlakeRef, github};
+use crate::{
+    changes::ChangedFiles, config::subflakes::SubflakesConfig, flake_ref::FlakeRef, github,
+};
 
 /// Command to generate a Github Actions matrix
 #[derive(Parser, Debug, Clone)]
@@ -19,6 +21,12 @@ pub struct GHMatrixCommand {
     #[arg(long, value_parser, value_delimiter = ',')]
     pub systems: Vec<System>,
 
+    /// Only include subflakes affected by the changes since the given git ref
+    ///
+    /// See `om ci run --since`.
+    #[arg(long, value_name = "GIT_REF")]
+    pub since: Option<String>,
+
     /// Nix command global options
     #[command(flatten)]
     pub nixcmd: NixCmd,
@@ -28,7 +36,12 @@ impl GHMatrixCommand {
     /// Run the command
     pub async fn run(&self, cfg: OmConfig) -> anyhow::Result<()> {
         let (config, _rest) = cfg.get_sub_config_under::<SubflakesConfig>("ci")?;
-        let matrix = github::matrix::GitHubMatrix::from(self.systems.clone(), &config);
+        let changes = match &self.since {
+            Some(git_ref) => Some(ChangedFiles::since(&cfg.flake_url, git_ref).await?),
+            None => None,
+        };
+        let matrix =
+            github::matrix::GitHubMatrix::from(self.systems.clone(), &config, changes.as_ref());
         println!("{}", serde_json::to_string(&matrix)?);
         Ok(())
     }
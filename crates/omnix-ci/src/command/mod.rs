@@ -1,5 +1,9 @@
 //! CLI commands for omnix-ci
 pub mod core;
+pub mod diff;
 pub mod gh_matrix;
+pub mod pipeline;
+pub mod plan;
 pub mod run;
 pub mod run_remote;
+pub mod update_lock;
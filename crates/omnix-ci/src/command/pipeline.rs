@@ -0,0 +1,77 @@
+//! The gitlab-pipeline and buildkite-pipeline commands
+use clap::Parser;
+use nix_rs::{command::NixCmd, flake::system::System};
+use omnix_common::config::OmConfig;
+
+use crate::{
+    changes::ChangedFiles,
+    config::subflakes::SubflakesConfig,
+    flake_ref::FlakeRef,
+    pipeline::{self, buildkite::BuildkitePipeline, gitlab::GitLabPipeline, RunnerTags},
+};
+
+/// Command to generate a pipeline YAML for a CI provider
+#[derive(Parser, Debug, Clone)]
+pub struct PipelineCommand {
+    /// Flake URL or github URL
+    ///
+    /// A specific omnix-ci configuration can be specified
+    /// using '#': e.g. `om ci gitlab-pipeline .#extra-tests`
+    #[arg(default_value = ".")]
+    pub flake_ref: FlakeRef,
+
+    /// Systems to include in the pipeline
+    #[arg(long, value_parser, value_delimiter = ',')]
+    pub systems: Vec<System>,
+
+    /// Tags selecting the runners (GitLab) or agents (Buildkite) for the jobs of a system
+    ///
+    /// Can be passed multiple times, e.g. `--runner-tags x86_64-linux=nix,linux --runner-tags aarch64-darwin=macos`.
+    /// Buildkite agent tags are of the form `key=value`, e.g. `--runner-tags aarch64-darwin=queue=macos`.
+    #[arg(long, value_name = "SYSTEM=TAGS")]
+    pub runner_tags: Vec<RunnerTags>,
+
+    /// Only include subflakes affected by the changes since the given git ref
+    ///
+    /// See `om ci run --since`.
+    #[arg(long, value_name = "GIT_REF")]
+    pub since: Option<String>,
+
+    /// Nix command global options
+    #[command(flatten)]
+    pub nixcmd: NixCmd,
+}
+
+/// The CI provider to generate a pipeline for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    /// GitLab CI
+    GitLab,
+    /// Buildkite
+    Buildkite,
+}
+
+impl PipelineCommand {
+    /// Run the command, printing the pipeline YAML for `provider`
+    pub async fn run(&self, cfg: OmConfig, provider: Provider) -> anyhow::Result<()> {
+        let (config, _rest) = cfg.get_sub_config_under::<SubflakesConfig>("ci")?;
+        let changes = match &self.since {
+            Some(git_ref) => Some(ChangedFiles::since(&cfg.flake_url, git_ref).await?),
+            None => None,
+        };
+        let config_name = cfg.reference.first().map_or("default", String::as_str);
+        let jobs = pipeline::jobs(
+            config_name,
+            self.systems.clone(),
+            &config,
+            changes.as_ref(),
+            &self.runner_tags,
+        );
+        let yaml = match provider {
+            Provider::GitLab => serde_yaml::to_string(&GitLabPipeline::from(jobs))?,
+            Provider::Buildkite => serde_yaml::to_string(&BuildkitePipeline::from(jobs)?)?,
+        };
+        print!("{}", yaml);
+        Ok(())
+    }
+}
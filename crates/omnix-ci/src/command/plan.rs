@@ -0,0 +1,285 @@
+//! The plan command
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf};
+
+use clap::Parser;
+use colored::Colorize;
+use nix_rs::{
+    config::NixConfig,
+    flake::{system::System, url::FlakeUrl},
+};
+use omnix_common::config::OmConfig;
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::config::{
+    subflake::SkipReason,
+    subflakes::{effective_config, SubflakesConfig},
+};
+
+use super::run::RunCommand;
+
+/// Command to print what `om ci run` would do, without building anything
+#[derive(Parser, Debug, Clone)]
+pub struct PlanCommand {
+    /// Print the plan as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// Include the CI configuration in effect (with `defaults` and `extends` resolved) in the plan
+    #[arg(long)]
+    pub show_config: bool,
+
+    /// The `om ci run` arguments to plan for
+    #[command(flatten)]
+    pub run_cmd: RunCommand,
+}
+
+impl PlanCommand {
+    /// Run the command
+    pub async fn run(&self, cfg: OmConfig) -> anyhow::Result<()> {
+        let nix_config = NixConfig::get().await.as_ref()?;
+        let mut plan = ci_plan(&self.run_cmd, &cfg, nix_config).await?;
+        if self.show_config {
+            let (config, _) = cfg.get_sub_config_under::<serde_json::Value>("ci")?;
+            plan.config = Some(effective_config(config).map_err(anyhow::Error::msg)?);
+        }
+        if self.json {
+            println!("{}", serde_json::to_string(&plan)?);
+        } else {
+            plan.print();
+        }
+        Ok(())
+    }
+}
+
+/// Resolve the CI configuration into a [Plan], the same way [super::run::ci_run] would.
+pub async fn ci_plan(
+    run_cmd: &RunCommand,
+    cfg: &OmConfig,
+    nix_config: &NixConfig,
+) -> anyhow::Result<Plan> {
+    let cmd = &run_cmd.nixcmd;
+    let systems = run_cmd.get_systems(cmd, nix_config).await?;
+    let (config, attrs) = cfg.get_sub_config_under::<SubflakesConfig>("ci")?;
+    let changes = run_cmd.get_changes(&cfg.flake_url).await?;
+
+    let subflakes = config
+        .select(attrs.first(), &systems, changes.as_ref())
+        .map(|(name, subflake, skip_reason)| {
+            let plan = match skip_reason {
+                Some(reason) => SubflakePlan::Skipped { reason },
+                None => SubflakePlan::Run {
+                    dir: subflake.dir.clone(),
+                    steps: subflake
+                        .steps
+                        .plan(cmd, run_cmd, &systems, &cfg.flake_url, subflake),
+                },
+            };
+            (name.clone(), plan)
+        })
+        .collect();
+
+    Ok(Plan {
+        flake: cfg.flake_url.clone(),
+        jobs: run_cmd.get_jobs(&config),
+        systems,
+        config: None,
+        subflakes,
+    })
+}
+
+/// What `om ci run` would do
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    /// The flake being built
+    pub flake: FlakeUrl,
+    /// The systems we would build for
+    pub systems: Vec<System>,
+    /// Maximum number of subflakes to run concurrently
+    pub jobs: usize,
+    /// The CI configuration in effect, if requested (see [PlanCommand::show_config])
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<serde_json::Value>,
+    /// Plan for each subflake
+    pub subflakes: BTreeMap<String, SubflakePlan>,
+}
+
+/// What `om ci run` would do with a subflake
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum SubflakePlan {
+    /// The subflake would be run
+    Run {
+        /// Subdirectory in which the flake lives
+        dir: String,
+        /// Steps, in the order they would be run
+        steps: Vec<StepPlan>,
+    },
+    /// The subflake would be skipped
+    Skipped {
+        /// Why it would be skipped
+        reason: SkipReason,
+    },
+}
+
+/// What `om ci run` would do for a single step
+#[derive(Debug, Clone, Serialize)]
+pub struct StepPlan {
+    /// Name of the step (custom steps are named `custom.<name>`)
+    pub name: String,
+    /// Why the step would be skipped, if it would be
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    /// The command the step would run, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<PlannedCommand>,
+}
+
+/// A command that would be run by a step
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedCommand {
+    /// The directory to run the command in, if not the current one
+    ///
+    /// Custom steps run in a (writeable) copy of the flake; this path is relative to it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// The command line, which can be copy-pasted to a shell
+    pub cli: String,
+}
+
+impl StepPlan {
+    /// A step that would run the given command
+    pub fn run(name: impl Into<String>, cmd: &Command) -> Self {
+        StepPlan {
+            name: name.into(),
+            skipped: None,
+            command: Some(PlannedCommand {
+                cwd: cmd.as_std().get_current_dir().map(PathBuf::from),
+                cli: nix_rs::command::to_cli(cmd),
+            }),
+        }
+    }
+
+    /// A step that would be run, but whose command cannot be known in advance
+    pub fn enabled(name: impl Into<String>) -> Self {
+        StepPlan {
+            name: name.into(),
+            skipped: None,
+            command: None,
+        }
+    }
+
+    /// A step that would be skipped for the given reason
+    pub fn skipped(name: impl Into<String>, reason: impl Display) -> Self {
+        StepPlan {
+            name: name.into(),
+            skipped: Some(reason.to_string()),
+            command: None,
+        }
+    }
+
+    fn print(&self) {
+        match (&self.skipped, &self.command) {
+            (Some(reason), _) => {
+                let msg = format!("skipped ({})", reason);
+                println!("   ⏭️  {} {}", self.name, msg.dimmed());
+            }
+            (None, Some(cmd)) => {
+                println!("   ▶️  {}", self.name.bold());
+                if let Some(cwd) = &cmd.cwd {
+                    println!("      {}", format!("(in {})", cwd.display()).dimmed());
+                }
+                println!("      {}", cmd.cli);
+            }
+            (None, None) => println!("   ▶️  {}", self.name.bold()),
+        }
+    }
+}
+
+impl Plan {
+    /// Print the plan in a human-readable form
+    pub fn print(&self) {
+        println!(
+            "{}",
+            format!(
+                "📝 Plan for {} on {} ({} subflake(s) at a time)",
+                self.flake,
+                self.systems
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                self.jobs
+            )
+            .bold()
+        );
+        if let Some(config) = &self.config {
+            println!("\n{}", "⚙️  Configuration in effect".bold());
+            // Printed as YAML, as in `om.yaml`
+            match serde_yaml::to_string(config) {
+                Ok(yaml) => print!("{}", yaml),
+                Err(err) => tracing::warn!("Unable to print the configuration: {}", err),
+            }
+        }
+        for (name, subflake) in &self.subflakes {
+            match subflake {
+                SubflakePlan::Skipped { reason } => {
+                    let msg = format!("skipped ({})", reason);
+                    println!("\n🍊 {} {}", name.italic(), msg.dimmed());
+                }
+                SubflakePlan::Run { dir, steps } => {
+                    println!(
+                        "\n🍎 {} {}",
+                        name.italic(),
+                        format!("(dir: {})", dir).dimmed()
+                    );
+                    for step in steps {
+                        step.print();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix_rs::command::NixCmd;
+
+    use crate::config::subflake::SubflakeConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_default_steps_plan() {
+        let subflake = SubflakeConfig::default();
+        let url = FlakeUrl("github:srid/haskell-multi-nix".to_string());
+        let steps = subflake.steps.plan(
+            &NixCmd::default(),
+            &RunCommand::default(),
+            &[],
+            &url,
+            &subflake,
+        );
+        let summary: Vec<_> = steps
+            .iter()
+            .map(|s| (s.name.as_str(), s.skipped.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("lockfile", None),
+                ("fmt", Some("disabled")),
+                ("build", None),
+                ("closure-size", Some("disabled")),
+                ("flake-check", Some("disabled")),
+                ("cache", Some("disabled")),
+            ]
+        );
+        let lockfile_cli = &steps[0].command.as_ref().unwrap().cli;
+        assert_eq!(
+            lockfile_cli,
+            "nix flake lock github:srid/haskell-multi-nix --no-update-lock-file"
+        );
+    }
+}
@@ -1,16 +1,19 @@
 //! The run command
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet},
     env,
-    io::Write,
+    io::{IsTerminal, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::{Context, Result};
+use chrono::Utc;
 use clap::Parser;
 use colored::Colorize;
 use nix_rs::{
-    command::NixCmd,
+    command::{with_log_prefix, NixCmd},
     config::NixConfig,
     flake::{functions::addstringcontext, system::System, url::FlakeUrl},
     info::NixInfo,
@@ -20,10 +23,26 @@ use nix_rs::{
 use omnix_common::config::OmConfig;
 use omnix_health::{traits::Checkable, NixHealth};
 use serde::{Deserialize, Serialize};
+use tabled::{
+    settings::{object::Rows, Color, Modify, Style},
+    Table, Tabled,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
 
 use crate::{
-    config::subflakes::SubflakesConfig, flake_ref::FlakeRef, github::actions::in_github_log_group,
-    step::core::StepsResult,
+    changes::ChangedFiles,
+    config::{
+        subflake::{SkipReason, SubflakeConfig},
+        subflakes::SubflakesConfig,
+    },
+    events::{self, Event},
+    flake_ref::FlakeRef,
+    github::{
+        self,
+        actions::{error_annotation, in_github_log_group},
+    },
+    provenance::RunMetadata,
+    step::core::{StepFailed, StepOutcome, StepStatus, StepsResult},
 };
 
 use super::run_remote;
@@ -33,8 +52,12 @@ use super::run_remote;
 #[derive(Parser, Debug, Clone)]
 pub struct RunCommand {
     /// Run `om ci run` remotely on the given store URI
-    #[clap(long)]
-    pub on: Option<StoreURI>,
+    ///
+    /// Can be passed several times, to run on several remotes concurrently;
+    /// their results are merged. Use `?systems=` to run a remote only for
+    /// the given (comma-separated) systems, e.g. `ssh://mac-mini?systems=aarch64-darwin`.
+    #[clap(long, value_name = "STORE_URI")]
+    pub on: Vec<StoreURI>,
 
     /// The systems list to build for. If empty, build for current system.
     ///
@@ -46,6 +69,23 @@ pub struct RunCommand {
     #[arg(long)]
     pub systems: Option<SystemsListFlakeRef>,
 
+    /// Maximum number of subflakes to run concurrently
+    ///
+    /// Overrides the `jobs` setting in the CI configuration. Defaults to 1.
+    /// When running more than one subflake at a time, log lines are prefixed
+    /// with the name of the subflake they belong to. Also bounds the number of
+    /// custom steps of a subflake to run concurrently.
+    #[arg(long)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Only run subflakes affected by the changes since the given git ref
+    ///
+    /// A subflake is affected by changes to its directory, to the local paths
+    /// it uses as flake inputs, and to `om.yaml`. Uncommitted changes are
+    /// included. Requires a local flake.
+    #[arg(long, value_name = "GIT_REF", conflicts_with = "on")]
+    pub since: Option<String>,
+
     /// Symlink to build results (as JSON)
     #[arg(
         long,
@@ -61,6 +101,19 @@ pub struct RunCommand {
     #[arg(long)]
     no_link: bool,
 
+    /// Also write the results as a JUnit XML report to the given path
+    ///
+    /// Each subflake is reported as a testsuite, and each of its steps as a
+    /// testcase. Use along with `--keep-going` to report failing steps.
+    #[arg(long, value_name = "PATH")]
+    pub junit: Option<PathBuf>,
+
+    /// Write a stream of lifecycle events, as newline-delimited JSON, to the given path (`-` for stdout)
+    ///
+    /// See <https://omnix.page/om/ci.html#events> for the schema.
+    #[arg(long, value_name = "PATH")]
+    pub events: Option<String>,
+
     /// Flake URL or github URL
     ///
     /// A specific configuration can be specified
@@ -68,10 +121,17 @@ pub struct RunCommand {
     #[arg(default_value = ".")]
     pub flake_ref: FlakeRef,
 
-    /// Print Github Actions log groups (enabled by default when run in Github Actions)
+    /// Print Github Actions log groups and error annotations, and write the job summary (enabled by default when run in Github Actions)
     #[clap(long, default_value_t = env::var("GITHUB_ACTION").is_ok())]
     pub github_output: bool,
 
+    /// Keep going when a step fails, recording the failure in the results JSON
+    ///
+    /// All subflakes and steps are run, and a summary of their outcome is
+    /// printed at the end. The command still fails if any step failed.
+    #[arg(long, short = 'k')]
+    pub keep_going: bool,
+
     /// Arguments for all steps
     #[command(flatten)]
     pub steps_args: crate::step::core::StepsArgs,
@@ -100,25 +160,36 @@ impl RunCommand {
     /// Override the `flake_ref` and `out_link`` for building locally.
     pub fn local_with(&self, flake_ref: FlakeRef, out_link: Option<PathBuf>) -> Self {
         let mut new = self.clone();
-        new.on = None; // Disable remote building
+        new.on = vec![]; // Disable remote building
         new.flake_ref = flake_ref;
         new.no_link = out_link.is_none();
         new.out_link = out_link;
+        new.junit = None; // The report is written locally, from the results JSON
+        new.events = None; // Events are written locally
         new
     }
 
+    /// Do not create a symlink to the build results JSON, as with `--no-link`
+    pub fn without_out_link(mut self) -> Self {
+        self.no_link = true;
+        self.out_link = None;
+        self
+    }
+
     /// Run the build command which decides whether to do ci run on current machine or a remote machine
-    pub async fn run(&self, cfg: OmConfig) -> anyhow::Result<()> {
-        match &self.on {
-            Some(store_uri) => {
-                run_remote::run_on_remote_store(&self.nixcmd, self, &cfg, store_uri).await
-            }
-            None => self.run_local(cfg).await,
+    pub async fn run(&self, cfg: &OmConfig) -> anyhow::Result<()> {
+        if let Some(path) = &self.events {
+            events::init(path)?;
+        }
+        if self.on.is_empty() {
+            self.run_local(cfg).await
+        } else {
+            run_remote::run_on_remote_stores(&self.nixcmd, self, cfg, &self.on).await
         }
     }
 
     /// Run [RunCommand] on local Nix store.
-    async fn run_local(&self, cfg: OmConfig) -> anyhow::Result<()> {
+    async fn run_local(&self, cfg: &OmConfig) -> anyhow::Result<()> {
         // TODO: We'll refactor this function to use steps
         // https://github.com/juspay/omnix/issues/216
 
@@ -134,8 +205,8 @@ impl RunCommand {
         // First, run the necessary health checks
         in_github_log_group("health", self.github_output, || async {
             tracing::info!("{}", "\n🫀 Performing health check".bold());
-            // check_nix_version(&cfg, nix_info).await?;
-            check_nix_version(&cfg, nix_info).await
+            // check_nix_version(cfg, nix_info).await?;
+            check_nix_version(cfg, nix_info).await
         })
         .await?;
 
@@ -144,9 +215,19 @@ impl RunCommand {
             "{}",
             format!("\n🤖 Running CI for {}", self.flake_ref).bold()
         );
-        let res = ci_run(&self.nixcmd, self, &cfg, &nix_info.nix_config).await?;
+        let (res, outcome) = match ci_run(&self.nixcmd, self, cfg, &nix_info.nix_config).await {
+            Ok(v) => v,
+            Err(err) => {
+                events::emit(Event::RunFinished {
+                    success: false,
+                    result_path: None,
+                    error: Some(format!("{:#}", err)),
+                });
+                return Err(err);
+            }
+        };
 
-        let msg = in_github_log_group::<anyhow::Result<String>, _, _>(
+        let (msg, results_path) = in_github_log_group::<anyhow::Result<(String, PathBuf)>, _, _>(
             "outlink",
             self.github_output,
             || async {
@@ -161,7 +242,15 @@ impl RunCommand {
                 let results_path =
                     addstringcontext::addstringcontext(&self.nixcmd, path.path(), m_out_link)
                         .await?;
-                println!("{}", results_path.display());
+                // With `--events -`, the path is reported in the `run-finished` event instead.
+                if self.events.as_deref() != Some("-") {
+                    println!("{}", results_path.display());
+                }
+                events::emit(Event::RunFinished {
+                    success: outcome.is_ok() && res.failures().next().is_none(),
+                    result_path: Some(results_path.clone()),
+                    error: outcome.as_ref().err().map(|err| format!("{:#}", err)),
+                });
 
                 let msg = format!(
                     "Result available at {:?}{}",
@@ -170,16 +259,54 @@ impl RunCommand {
                         .map(|p| format!(" and symlinked at {:?}", p))
                         .unwrap_or_default()
                 );
-                Ok(msg)
+                Ok((msg, results_path))
             },
         )
         .await?;
 
         tracing::info!("{}", msg);
 
+        // Report the partial results too, if the run stopped at a failing step
+        if self.github_output {
+            res.report_to_github(&results_path)?;
+        }
+
+        if let Some(junit) = &self.junit {
+            res.write_junit(junit)?;
+        }
+
+        res.print_summary();
+        outcome?;
+
+        let failures = res.failures().count();
+        if failures > 0 {
+            anyhow::bail!("{} step(s) failed", failures);
+        }
+
         Ok(())
     }
 
+    /// Get the maximum number of subflakes to run concurrently
+    pub fn get_jobs(&self, config: &SubflakesConfig) -> usize {
+        self.jobs.or(config.jobs).map_or(1, NonZeroUsize::get)
+    }
+
+    /// Get the files changed since the `--since` ref, if one was given
+    pub async fn get_changes(&self, url: &FlakeUrl) -> Result<Option<ChangedFiles>> {
+        match &self.since {
+            None => Ok(None),
+            Some(git_ref) => {
+                let changes = ChangedFiles::since(url, git_ref).await?;
+                tracing::info!(
+                    "🔍 {} file(s) changed since {}",
+                    changes.files.len(),
+                    git_ref
+                );
+                Ok(Some(changes))
+            }
+        }
+    }
+
     /// Get the systems to build for
     pub async fn get_systems(&self, cmd: &NixCmd, nix_config: &NixConfig) -> Result<Vec<System>> {
         match &self.systems {
@@ -199,7 +326,7 @@ impl RunCommand {
     pub fn to_cli_args(&self) -> Vec<String> {
         let mut args = vec![];
 
-        if let Some(uri) = self.on.as_ref() {
+        for uri in &self.on {
             args.push("--on".to_owned());
             args.push(uri.to_string());
         }
@@ -209,6 +336,16 @@ impl RunCommand {
             args.push(systems.0 .0.clone());
         }
 
+        if let Some(jobs) = self.jobs {
+            args.push("--jobs".to_string());
+            args.push(jobs.to_string());
+        }
+
+        if let Some(since) = self.since.as_ref() {
+            args.push("--since".to_string());
+            args.push(since.clone());
+        }
+
         if let Some(out_link) = self.out_link.as_ref() {
             args.push("--out-link".to_string());
             args.push(out_link.to_string_lossy().to_string());
@@ -218,6 +355,20 @@ impl RunCommand {
             args.push("--no-link".to_string());
         }
 
+        if self.keep_going {
+            args.push("--keep-going".to_string());
+        }
+
+        if let Some(junit) = self.junit.as_ref() {
+            args.push("--junit".to_string());
+            args.push(junit.to_string_lossy().to_string());
+        }
+
+        if let Some(events) = self.events.as_ref() {
+            args.push("--events".to_string());
+            args.push(events.clone());
+        }
+
         args.push(self.flake_ref.to_string());
 
         args.extend(self.steps_args.to_cli_args());
@@ -241,76 +392,245 @@ pub async fn check_nix_version(cfg: &OmConfig, nix_info: &NixInfo) -> anyhow::Re
 }
 
 /// Run CI for all subflakes
+///
+/// Returns the results, along with the error that stopped the run (i.e., unless `--keep-going`), if any. In that case, the results are partial: they cover the steps run until then.
 pub async fn ci_run(
     cmd: &NixCmd,
     run_cmd: &RunCommand,
     cfg: &OmConfig,
     nix_config: &NixConfig,
-) -> anyhow::Result<RunResult> {
-    let mut res = HashMap::new();
+) -> anyhow::Result<(RunResult, anyhow::Result<()>)> {
+    let started_at = Utc::now();
     let systems = run_cmd.get_systems(cmd, nix_config).await?;
+    events::emit(Event::RunStarted {
+        flake: cfg.flake_url.clone(),
+        systems: systems.clone(),
+    });
 
     let (config, attrs) = cfg.get_sub_config_under::<SubflakesConfig>("ci")?;
 
     // User's filter by subflake name
     let only_subflake = attrs.first();
-
-    for (subflake_name, subflake) in &config.0 {
-        let name = subflake_name.italic();
-
-        if let Some(s) = only_subflake {
-            if s != subflake_name {
-                tracing::info!("\n🍊 {} {}", name, "skipped (deselected out)".dimmed());
-                continue;
+    let changes = run_cmd.get_changes(&cfg.flake_url).await?;
+
+    let mut selected = vec![];
+    let mut skipped = BTreeMap::new();
+    for (subflake_name, subflake, skip_reason) in
+        config.select(only_subflake, &systems, changes.as_ref())
+    {
+        match skip_reason {
+            Some(reason) => {
+                let msg = format!("skipped ({})", reason);
+                tracing::info!("\n🍊 {} {}", subflake_name.italic(), msg.dimmed());
+                events::emit_for(Some(subflake_name), Event::SubflakeSkipped { reason });
+                skipped.insert(subflake_name.clone(), reason);
+            }
+            None => {
+                events::emit_for(Some(subflake_name), Event::SubflakeSelected);
+                selected.push((subflake_name.clone(), subflake.clone()))
             }
         }
+    }
 
-        let compatible_system = subflake.can_run_on(&systems);
-        if !compatible_system {
-            tracing::info!(
-                "\n🍊 {} {}",
-                name,
-                "skipped (cannot run on this system)".dimmed()
-            );
-            continue;
+    let jobs = run_cmd.get_jobs(&config);
+    let (res, outcome) = if jobs > 1 && selected.len() > 1 {
+        run_subflakes_concurrently(cmd, run_cmd, &systems, &cfg.flake_url, selected, jobs).await
+    } else {
+        let mut res = BTreeMap::new();
+        let mut outcome = Ok(());
+        for (subflake_name, subflake) in selected {
+            let name = subflake_name.italic();
+            let (steps_res, steps_outcome) = in_github_log_group(
+                &format!("subflake={}", name),
+                run_cmd.github_output,
+                || async {
+                    tracing::info!("\n🍎 {}", name);
+                    let steps =
+                        subflake
+                            .steps
+                            .run(cmd, run_cmd, &systems, &cfg.flake_url, &subflake, jobs);
+                    events::in_subflake(subflake_name.clone(), steps).await
+                },
+            )
+            .await;
+            if run_cmd.github_output {
+                annotate_failures(&subflake_name, &steps_res, steps_outcome.as_ref().err());
+            }
+            res.insert(subflake_name, steps_res);
+            if steps_outcome.is_err() {
+                outcome = steps_outcome;
+                break;
+            }
+        }
+        (res, outcome)
+    };
+
+    // Missing provenance (e.g., a flake outside of a git repository) must not throw away the results of the run
+    let metadata = match RunMetadata::gather(cmd, cfg, started_at).await {
+        Ok(metadata) => Some(metadata),
+        Err(err) => {
+            tracing::warn!("Not recording the run's metadata: {:#}", err);
+            None
         }
+    };
+    let res = RunResult {
+        systems,
+        flake: cfg.flake_url.clone(),
+        result: res,
+        skipped,
+        metadata,
+        runs: BTreeMap::new(),
+    };
 
-        let steps_res = in_github_log_group(
-            &format!("subflake={}", name),
-            run_cmd.github_output,
-            || async {
-                tracing::info!("\n🍎 {}", name);
-                subflake
+    if outcome.is_ok() && res.failures().next().is_none() {
+        tracing::info!("\n🥳 Success!");
+    }
+
+    Ok((res, outcome))
+}
+
+/// Run CI for the given subflakes, running at most `jobs` of them at a time.
+///
+/// Log output of each subflake is prefixed with its name. The first failure aborts the remaining subflakes; the results of those that finished (including the failing one) are returned along with it.
+async fn run_subflakes_concurrently(
+    cmd: &NixCmd,
+    run_cmd: &RunCommand,
+    systems: &[System],
+    url: &FlakeUrl,
+    subflakes: Vec<(String, SubflakeConfig)>,
+    jobs: usize,
+) -> (BTreeMap<String, StepsResult>, anyhow::Result<()>) {
+    tracing::info!(
+        "\n🍎 Running {} subflakes, {} at a time",
+        subflakes.len(),
+        jobs
+    );
+    let semaphore = Arc::new(Semaphore::new(jobs));
+    let mut tasks = JoinSet::new();
+    for (subflake_name, subflake) in subflakes {
+        let (cmd, run_cmd, systems, url) =
+            (cmd.clone(), run_cmd.clone(), systems.to_vec(), url.clone());
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let prefix = format!("[{}]", subflake_name).italic().to_string();
+            let (steps_res, outcome) = with_log_prefix(prefix, async {
+                tracing::info!("🍎 {}", subflake_name.italic());
+                let steps = subflake
                     .steps
-                    .run(cmd, run_cmd, &systems, &cfg.flake_url, subflake)
-                    .await
-            },
-        )
-        .await?;
-        res.insert(subflake_name.clone(), steps_res);
+                    .run(&cmd, &run_cmd, &systems, &url, &subflake, jobs);
+                events::in_subflake(subflake_name.clone(), steps).await
+            })
+            .await;
+            if run_cmd.github_output {
+                annotate_failures(&subflake_name, &steps_res, outcome.as_ref().err());
+            }
+            anyhow::Ok((subflake_name, steps_res, outcome))
+        });
     }
 
-    tracing::info!("\n🥳 Success!");
+    // Results are collected into a `BTreeMap`, so their order does not depend on which subflake finished first.
+    let mut res = BTreeMap::new();
+    while let Some(joined) = tasks.join_next().await {
+        // Returning early drops `tasks`, which aborts the subflakes still running.
+        let (subflake_name, steps_res, outcome) = match joined.map_err(anyhow::Error::from) {
+            Ok(Ok(v)) => v,
+            Ok(Err(err)) | Err(err) => return (res, Err(err)),
+        };
+        res.insert(subflake_name, steps_res);
+        if outcome.is_err() {
+            return (res, outcome);
+        }
+    }
+    (res, Ok(()))
+}
 
-    Ok(RunResult {
-        systems,
-        flake: cfg.flake_url.clone(),
-        result: res,
-    })
+/// Create GitHub Actions error annotations for the failed steps of a subflake
+///
+/// `error` is the error that stopped its steps, if any; it is annotated unless it is the failure of a step (which is recorded in `res`).
+fn annotate_failures(subflake: &str, res: &StepsResult, error: Option<&anyhow::Error>) {
+    let annotate = |step: Option<&str>, error: &str| {
+        let title = match step {
+            Some(step) => format!("om ci: {} › {} failed", subflake, step),
+            None => format!("om ci: {} failed", subflake),
+        };
+        let message = match nix_rs::events::failed_derivation(error) {
+            Some(drv) => format!("{}\n\nFailing derivation: {}", error, drv.display()),
+            None => error.to_string(),
+        };
+        error_annotation(&title, &message);
+    };
+    for step in res.failures() {
+        if let StepStatus::Failure { error } = &step.status {
+            annotate(Some(&step.name), error);
+        }
+    }
+    if let Some(err) = error.filter(|err| !err.is::<StepFailed>()) {
+        annotate(None, &format!("{:#}", err));
+    }
 }
 
 /// Results of the 'ci run' command
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RunResult {
     /// The systems we are building for
-    systems: Vec<System>,
+    pub systems: Vec<System>,
     /// The flake being built
-    flake: FlakeUrl,
+    pub flake: FlakeUrl,
     /// CI result for each subflake
-    result: HashMap<String, StepsResult>,
+    pub result: BTreeMap<String, StepsResult>,
+    /// Subflakes that were not run, and why
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub skipped: BTreeMap<String, SkipReason>,
+    /// Provenance of this run (absent in results of older omnix versions, or if it could not be gathered)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<RunMetadata>,
+    /// Provenance of each of the runs these results were merged from (see [RunResult::merge]), keyed by their label
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub runs: BTreeMap<String, RunMetadata>,
 }
 
 impl RunResult {
+    /// Merge the results of several runs (e.g., on different remotes), each identified by a label
+    ///
+    /// Subflakes are keyed as `<subflake>@<label>` in the merged result, and
+    /// the metadata of each run is kept under `runs`. Labels must be unique.
+    pub fn merge(flake: FlakeUrl, results: Vec<(String, RunResult)>) -> anyhow::Result<Self> {
+        let mut merged = RunResult {
+            systems: vec![],
+            flake,
+            result: BTreeMap::new(),
+            skipped: BTreeMap::new(),
+            metadata: None,
+            runs: BTreeMap::new(),
+        };
+        let mut labels = BTreeSet::new();
+        for (label, res) in results {
+            if !labels.insert(label.clone()) {
+                anyhow::bail!("Cannot merge the results of two runs labelled {:?}", label);
+            }
+            if let Some(metadata) = res.metadata {
+                merged.runs.insert(label.clone(), metadata);
+            }
+            for system in res.systems {
+                if !merged.systems.contains(&system) {
+                    merged.systems.push(system);
+                }
+            }
+            for (subflake, steps_res) in res.result {
+                merged
+                    .result
+                    .insert(format!("{}@{}", subflake, label), steps_res);
+            }
+            for (subflake, reason) in res.skipped {
+                merged
+                    .skipped
+                    .insert(format!("{}@{}", subflake, label), reason);
+            }
+        }
+        Ok(merged)
+    }
+
     /// Get all store paths mentioned in this type.
     pub fn all_out_paths(&self) -> Vec<StorePath> {
         let mut res = vec![];
@@ -321,4 +641,156 @@ impl RunResult {
         }
         res
     }
+
+    /// Write a JUnit XML report of these results to the given path
+    pub fn write_junit(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, crate::junit::to_junit_xml(self))
+            .with_context(|| format!("Unable to write JUnit report to {:?}", path))?;
+        tracing::info!("JUnit report written to {:?}", path);
+        Ok(())
+    }
+
+    /// Report these results to GitHub Actions
+    ///
+    /// Appends a Markdown summary to `$GITHUB_STEP_SUMMARY`, and sets the `result` output (in `$GITHUB_OUTPUT`) to `results_path`.
+    pub fn report_to_github(&self, results_path: &Path) -> anyhow::Result<()> {
+        github::actions::append_step_summary(&github::summary::to_markdown(self))?;
+        github::actions::set_output("result", &results_path.to_string_lossy())
+    }
+
+    /// All the steps that failed, along with the name of their subflake
+    pub fn failures(&self) -> impl Iterator<Item = (&String, &StepOutcome)> {
+        self.result
+            .iter()
+            .flat_map(|(name, steps_res)| steps_res.failures().map(move |step| (name, step)))
+    }
+
+    /// Print a table summarizing the outcome of every step of every subflake, and the subflakes skipped
+    pub fn print_summary(&self) {
+        if !self.skipped.is_empty() {
+            let skipped = self
+                .skipped
+                .iter()
+                .map(|(name, reason)| format!("{} ({})", name, reason))
+                .collect::<Vec<_>>()
+                .join(", ");
+            tracing::info!("\n🍊 Skipped subflakes: {}", skipped.dimmed());
+        }
+
+        let steps: Vec<(&String, &StepOutcome)> = self
+            .result
+            .iter()
+            .flat_map(|(subflake, steps_res)| {
+                steps_res.steps.iter().map(move |step| (subflake, step))
+            })
+            .collect();
+        if steps.is_empty() {
+            return;
+        }
+        let mut table = Table::new(steps.iter().map(|(subflake, step)| SummaryRow {
+            subflake: subflake.to_string(),
+            step: step.name.clone(),
+            status: match &step.status {
+                StepStatus::Success => "✅ success".to_string(),
+                StepStatus::Failure { error } => format!("❌ failed: {}", first_line(error)),
+                StepStatus::Skipped { reason } => format!("⏭️  skipped ({})", reason),
+            },
+        }));
+        table.with(Style::rounded());
+        for (i, (_, step)) in steps
+            .iter()
+            .enumerate()
+            .filter(|_| std::io::stderr().is_terminal())
+        {
+            let color = match step.status {
+                StepStatus::Success => Color::FG_GREEN,
+                StepStatus::Failure { .. } => Color::FG_RED,
+                StepStatus::Skipped { .. } => Color::FG_BRIGHT_BLACK,
+            };
+            // Row 0 is the header
+            table.with(Modify::new(Rows::single(i + 1)).with(color));
+        }
+        tracing::info!("\n{}\n{}", "📋 Summary".bold(), table);
+    }
+}
+
+/// Row in the table printed by [RunResult::print_summary]
+#[derive(Tabled)]
+struct SummaryRow {
+    subflake: String,
+    step: String,
+    status: String,
+}
+
+/// The first line of a (possibly multi-line) error message
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_with_cli_args() {
+        let run_cmd = RunCommand::parse_from(["run", "--keep-going", "--on", "ssh://builder"]);
+        let remote_cmd = run_cmd.local_with(
+            FlakeRef::Flake(FlakeUrl("/nix/store/abc-source".to_string())),
+            Some(PathBuf::from("/tmp/om-ci-abc/result")),
+        );
+        let mut args = vec!["run".to_string()];
+        args.extend(remote_cmd.to_cli_args());
+        let parsed = RunCommand::try_parse_from(&args).unwrap();
+        assert_eq!(
+            parsed.get_out_link(),
+            Some(Path::new("/tmp/om-ci-abc/result"))
+        );
+        assert!(parsed.keep_going);
+        assert!(parsed.on.is_empty());
+
+        let remote_cmd = run_cmd.local_with(
+            FlakeRef::Flake(FlakeUrl("/nix/store/abc-source".to_string())),
+            None,
+        );
+        let mut args = vec!["run".to_string()];
+        args.extend(remote_cmd.to_cli_args());
+        let parsed = RunCommand::try_parse_from(&args).unwrap();
+        assert_eq!(parsed.get_out_link(), None);
+    }
+
+    #[test]
+    fn test_merge_results() {
+        let res = |system: &str| RunResult {
+            systems: vec![System::from(system.to_string())],
+            flake: FlakeUrl(format!("/tmp/{}", system)),
+            result: BTreeMap::from([("ROOT".to_string(), StepsResult::default())]),
+            skipped: BTreeMap::from([("doc".to_string(), SkipReason::Disabled)]),
+            metadata: None,
+            runs: BTreeMap::new(),
+        };
+        let merged = RunResult::merge(
+            FlakeUrl(".".to_string()),
+            vec![
+                ("x86_64-linux".to_string(), res("x86_64-linux")),
+                ("aarch64-linux".to_string(), res("aarch64-linux")),
+            ],
+        )
+        .unwrap();
+        assert_eq!(merged.flake, FlakeUrl(".".to_string()));
+        assert_eq!(merged.systems.len(), 2);
+        assert_eq!(
+            merged.result.keys().collect::<Vec<_>>(),
+            vec!["ROOT@aarch64-linux", "ROOT@x86_64-linux"]
+        );
+        assert_eq!(merged.skipped.len(), 2);
+
+        let duplicate = RunResult::merge(
+            FlakeUrl(".".to_string()),
+            vec![
+                ("myserver".to_string(), res("x86_64-linux")),
+                ("myserver".to_string(), res("aarch64-linux")),
+            ],
+        );
+        assert!(duplicate.is_err());
+    }
 }
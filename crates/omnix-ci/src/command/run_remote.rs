@@ -1,105 +1,300 @@
 //! Functions for running `ci run` on remote machine.
 
+use anyhow::Context;
 use colored::Colorize;
+use futures_util::future::{join_all, try_join_all};
 use nix_rs::{
     command::{CommandError, NixCmd},
     copy::{nix_copy, NixCopyOptions},
     flake::{
-        functions::metadata::{FlakeMetadata, FlakeMetadataInput},
+        functions::{
+            addstringcontext,
+            metadata::{FlakeMetadata, FlakeMetadataInput},
+        },
+        system::System,
         url::FlakeUrl,
     },
-    store::{command::NixStoreCmd, path::StorePath, uri::StoreURI},
+    store::{
+        command::NixStoreCmd,
+        path::StorePath,
+        uri::{SSHStoreURI, StoreURI},
+    },
+    system_list::SystemsListFlakeRef,
 };
 use omnix_common::config::OmConfig;
 use std::{
-    ffi::{OsStr, OsString},
-    os::unix::ffi::OsStringExt,
+    collections::BTreeSet,
+    ffi::OsStr,
+    io::Write,
     path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
 };
-use tokio::process::Command;
 
-use super::run::RunCommand;
+use super::run::{RunCommand, RunResult};
+use crate::events::{self, Event};
 
 /// Path to Rust source corresponding to this (running) instance of Omnix
 const OMNIX_SOURCE: &str = env!("OMNIX_SOURCE");
 
-/// Like [RunCommand::run] but run on a remote Nix store.
-pub async fn run_on_remote_store(
+/// A run of `om ci run` on a remote, possibly for a single system only (per its `?systems=`)
+struct RemoteRun<'a> {
+    store_uri: &'a StoreURI,
+    ssh_uri: &'a SSHStoreURI,
+    system: Option<&'a System>,
+}
+
+impl RemoteRun<'_> {
+    /// Identifies this run in merged results: its SSH destination, and system if any (e.g. `admin@builder:2222/x86_64-linux`)
+    fn label(&self) -> String {
+        let mut label = self.ssh_uri.to_string();
+        if let Some(port) = self.ssh_uri.port {
+            label.push_str(&format!(":{}", port));
+        }
+        if let Some(system) = self.system {
+            label.push_str(&format!("/{}", system));
+        }
+        label
+    }
+}
+
+/// Like [RunCommand::run] but run on the given remote Nix stores, concurrently.
+///
+/// A remote with `?systems=` is run once for each of those systems. If there
+/// is more than one run, their results are merged (see [RunResult::merge]).
+pub async fn run_on_remote_stores(
     nixcmd: &NixCmd,
     run_cmd: &RunCommand,
     cfg: &OmConfig,
-    store_uri: &StoreURI,
+    store_uris: &[StoreURI],
 ) -> anyhow::Result<()> {
-    let StoreURI::SSH(ssh_uri, opts) = store_uri;
-    tracing::info!(
-        "{}",
-        format!("\n🛜 Running CI remotely on {} ({:?})", ssh_uri, opts).bold()
-    );
+    let mut runs = vec![];
+    for store_uri in store_uris {
+        let StoreURI::SSH(ssh_uri, opts) = store_uri else {
+            anyhow::bail!(
+                "Cannot run CI on {}; only ssh:// and ssh-ng:// stores are supported",
+                store_uri
+            );
+        };
+        if opts.systems.is_empty() {
+            runs.push(RemoteRun {
+                store_uri,
+                ssh_uri,
+                system: None,
+            });
+        }
+        for system in &opts.systems {
+            runs.push(RemoteRun {
+                store_uri,
+                ssh_uri,
+                system: Some(system),
+            });
+        }
+    }
 
-    let (flake_closure, local_flake_url) = &cache_flake(nixcmd, cfg, opts.copy_inputs).await?;
-    let omnix_source = PathBuf::from(OMNIX_SOURCE);
+    let mut labels = BTreeSet::new();
+    for run in &runs {
+        if !labels.insert(run.label()) {
+            anyhow::bail!(
+                "More than one run of CI would be labelled {:?}; pass distinct `systems=` to the remotes on the same host",
+                run.label()
+            );
+        }
+    }
 
+    let copy_inputs = store_uris.iter().any(|uri| uri.get_options().copy_inputs);
+    let (flake_closure, local_flake_url) = &cache_flake(nixcmd, cfg, copy_inputs).await?;
+    let omnix_source = PathBuf::from(OMNIX_SOURCE);
     let paths_to_push = vec![omnix_source, flake_closure.clone()];
 
-    // First, copy the flake and omnix source to the remote store, because we will be needing them when running over ssh.
-    nix_copy_to_remote(nixcmd, store_uri, &paths_to_push).await?;
+    // First, copy the flake and omnix source to the remote stores, because we will be needing them when running over ssh.
+    try_join_all(
+        store_uris
+            .iter()
+            .map(|store_uri| nix_copy_to_remote(nixcmd, store_uri, &paths_to_push)),
+    )
+    .await?;
+
+    // Subflakes are only suffixed by the run's label if results are merged
+    let merged = runs.len() > 1;
+    let outcomes = join_all(
+        runs.iter()
+            .map(|run| run_on_remote(nixcmd, run_cmd, run, merged, local_flake_url)),
+    )
+    .await;
+    let mut errors = vec![];
+    let mut results = vec![];
+    for (run, (result_path, res)) in runs.iter().zip(outcomes) {
+        if let Err(err) = res {
+            tracing::error!("❌ CI failed on {}: {:#}", run.ssh_uri, err);
+            errors.push(format!("{} ({})", run.ssh_uri, run.label()));
+        }
+        if let Some(path) = result_path {
+            results.push((run.label(), path));
+        }
+    }
 
-    // If out-link is requested, we need to copy the results back to local store - so that when we create the out-link *locally* the paths in it refer to valid paths in the local store. Thus, --out-link can be used to trick Omnix into copying all built paths back.
     if let Some(out_link) = run_cmd.get_out_link() {
-        // A temporary location on ssh remote to hold the result
-        let tmpdir = parse_path_line(
-            &run_ssh_with_output(
-                &ssh_uri.to_string(),
-                &nixpkgs_cmd("coreutils", &["mktemp", "-d", "-t", "om.json.XXXXXX"]),
-            )
-            .await?,
-        );
-        let om_json_path = tmpdir.join("om.json");
-
-        // Then, SSH and run the same `om ci run` CLI but without the `--on` argument but with `--out-link` pointing to the temporary location.
-        run_ssh(
-            &ssh_uri.to_string(),
-            &om_cli_with(
-                run_cmd.local_with(local_flake_url.clone().into(), Some(om_json_path.clone())),
-            ),
+        let result_path = match results.as_slice() {
+            [] => None,
+            [(_, path)] if !merged => {
+                // Write the local out-link
+                let nix_store = NixStoreCmd {};
+                nix_store.nix_store_add_root(out_link, &[path]).await?;
+                Some(path.as_path().clone())
+            }
+            _ => Some(merge_results(nixcmd, cfg, &results, out_link).await?),
+        };
+        if let Some(result_path) = result_path {
+            tracing::info!(
+                "Results available at {:?} symlinked at {:?}",
+                result_path,
+                out_link
+            );
+            if run_cmd.junit.is_some() || run_cmd.github_output {
+                let res: RunResult = serde_json::from_reader(std::fs::File::open(&result_path)?)?;
+                if let Some(junit) = &run_cmd.junit {
+                    res.write_junit(junit)?;
+                }
+                if run_cmd.github_output {
+                    res.report_to_github(&result_path)?;
+                }
+            }
+            events::emit(Event::RunFinished {
+                success: errors.is_empty(),
+                result_path: Some(result_path),
+                error: None,
+            });
+        }
+    } else {
+        if run_cmd.junit.is_some() {
+            tracing::warn!("Not writing JUnit report, because --no-link was passed");
+        }
+        if run_cmd.github_output {
+            tracing::warn!("Not writing GitHub job summary, because --no-link was passed");
+        }
+        events::emit(Event::RunFinished {
+            success: errors.is_empty(),
+            result_path: None,
+            error: None,
+        });
+    }
+
+    if !errors.is_empty() {
+        anyhow::bail!("CI failed on {}", errors.join(", "));
+    }
+    Ok(())
+}
+
+/// Run `om ci run` on the given remote, returning the path to its results (copied back to the local store), if an out-link is requested.
+///
+/// The results are copied back even if the run failed; if it stopped at a failing step, they cover the steps run until then.
+async fn run_on_remote(
+    nixcmd: &NixCmd,
+    run_cmd: &RunCommand,
+    run: &RemoteRun<'_>,
+    merged: bool,
+    local_flake_url: &FlakeUrl,
+) -> (Option<StorePath>, anyhow::Result<()>) {
+    tracing::info!(
+        "{}",
+        format!(
+            "\n🛜 Running CI remotely on {} ({:?})",
+            run.ssh_uri,
+            run.store_uri.get_options()
         )
-        .await?;
-
-        // Get the out-link store path.
-        let om_result_path: StorePath = StorePath::new(parse_path_line(
-            &run_ssh_with_output(
-                &ssh_uri.to_string(),
-                &nixpkgs_cmd(
-                    "coreutils",
-                    &["readlink", om_json_path.to_string_lossy().as_ref()],
-                ),
-            )
-            .await?,
-        ));
-
-        // Copy the results back to local store (the out-link).
-        tracing::info!("{}", "📦 Copying results back to local store".bold());
-        nix_copy_from_remote(nixcmd, store_uri, &[&om_result_path]).await?;
-
-        // Write the local out-link
-        let nix_store = NixStoreCmd {};
-        nix_store
-            .nix_store_add_root(out_link, &[&om_result_path])
-            .await?;
-        tracing::info!(
-            "Results available at {:?} symlinked at {:?}",
-            om_result_path.as_path(),
-            out_link
-        );
+        .bold()
+    );
+    // If out-link is requested, we need to copy the results back to local store - so that when we create the out-link *locally* the paths in it refer to valid paths in the local store. Thus, --out-link can be used to trick Omnix into copying all built paths back.
+    // Until then, the results are symlinked in a temporary directory on the remote, so they are not garbage collected.
+    let tmpdir = if run_cmd.get_out_link().is_some() {
+        match ssh_mktemp(run.ssh_uri).await {
+            Ok(dir) => Some(dir),
+            Err(err) => return (None, Err(err)),
+        }
     } else {
-        // Then, SSH and run the same `om ci run` CLI but without the `--on` argument.
-        run_ssh(
-            &ssh_uri.to_string(),
-            &om_cli_with(run_cmd.local_with(local_flake_url.clone().into(), None)),
+        None
+    };
+
+    // SSH and run the same `om ci run` CLI but without the `--on` argument, reporting its results path in its events.
+    let mut remote_cmd = run_cmd.local_with(
+        local_flake_url.clone().into(),
+        tmpdir.as_ref().map(|dir| dir.join("result")),
+    );
+    remote_cmd.events = Some("-".to_string());
+    if let Some(system) = run.system {
+        remote_cmd.systems = Some(SystemsListFlakeRef::from_str(system.as_ref()).unwrap());
+    }
+    let label = merged.then(|| run.label());
+    let (result_path, res) =
+        run_ssh_om(run.ssh_uri, label.as_deref(), &om_cli_with(remote_cmd)).await;
+
+    let Some(tmpdir) = tmpdir else {
+        return (None, res);
+    };
+    let copied = copy_results_back(nixcmd, run, result_path).await;
+    if let Err(err) = ssh_rm(run.ssh_uri, &tmpdir).await {
+        tracing::warn!(
+            "Unable to remove {:?} on {}: {:#}",
+            tmpdir,
+            run.ssh_uri,
+            err
+        );
+    }
+    match copied {
+        Ok(om_result_path) => (Some(om_result_path), res),
+        // Without results, the run's own error is the interesting one
+        Err(err) => (None, res.and(Err(err))),
+    }
+}
+
+/// Copy the results JSON reported by a remote run (and the paths it refers to) back to local store.
+async fn copy_results_back(
+    nixcmd: &NixCmd,
+    run: &RemoteRun<'_>,
+    result_path: Option<PathBuf>,
+) -> anyhow::Result<StorePath> {
+    let om_result_path = StorePath::new(
+        result_path.with_context(|| format!("{} did not report a results path", run.ssh_uri))?,
+    );
+    tracing::info!(
+        "{}",
+        format!(
+            "📦 Copying results back to local store from {}",
+            run.ssh_uri
         )
-        .await?;
+        .bold()
+    );
+    nix_copy_from_remote(nixcmd, run.store_uri, &[&om_result_path]).await?;
+    Ok(om_result_path)
+}
+
+/// Merge the results of the remote runs into a single results JSON, symlinked at `out_link`
+async fn merge_results(
+    nixcmd: &NixCmd,
+    cfg: &OmConfig,
+    results: &[(String, StorePath)],
+    out_link: &Path,
+) -> anyhow::Result<PathBuf> {
+    let mut run_results = vec![];
+    for (label, path) in results {
+        let res: RunResult = serde_json::from_reader(std::fs::File::open(path.as_path())?)
+            .with_context(|| format!("Unable to parse results in {}", path))?;
+        run_results.push((label.clone(), res));
     }
-    Ok(())
+    let merged = RunResult::merge(cfg.flake_url.clone(), run_results)?;
+    let mut file = tempfile::Builder::new()
+        .prefix("om-ci-results-")
+        .suffix(".json")
+        .tempfile()?;
+    file.write_all(serde_json::to_string(&merged)?.as_bytes())?;
+    let results_path =
+        addstringcontext::addstringcontext(nixcmd, file.path(), Some(out_link)).await?;
+    Ok(results_path)
 }
 
 async fn nix_copy_to_remote<I, P>(
@@ -144,23 +339,6 @@ where
     .await
 }
 
-fn parse_path_line(bytes: &[u8]) -> PathBuf {
-    let trimmed_bytes = bytes.trim_ascii_end();
-    PathBuf::from(OsString::from_vec(trimmed_bytes.to_vec()))
-}
-
-/// Construct CLI arguments for running a program from nixpkgs using given arguments
-fn nixpkgs_cmd(package: &str, cmd: &[&str]) -> Vec<String> {
-    let mut args = vec![
-        "nix".to_owned(),
-        "shell".to_owned(),
-        format!("nixpkgs#{}", package),
-    ];
-    args.push("-c".to_owned());
-    args.extend(cmd.iter().map(|s| s.to_string()));
-    args
-}
-
 /// Return the locally cached [FlakeUrl] for the given flake url that points to same selected [ConfigRef].
 async fn cache_flake(
     nixcmd: &NixCmd,
@@ -206,38 +384,97 @@ fn om_cli_with(run_cmd: RunCommand) -> Vec<String> {
     args
 }
 
-/// Run SSH command with given arguments.
-async fn run_ssh(host: &str, args: &[String]) -> anyhow::Result<()> {
+/// The `ssh` command running the given command on the host
+fn ssh_cmd(ssh_uri: &SSHStoreURI, command: String) -> Command {
     let mut cmd = Command::new("ssh");
-    cmd.args([host, &shell_words::join(args)]);
+    cmd.args(ssh_uri.ssh_args());
+    cmd.args([ssh_uri.to_string(), command]);
+    cmd
+}
 
+/// Create a new temporary directory on the host, returning its path
+async fn ssh_mktemp(ssh_uri: &SSHStoreURI) -> anyhow::Result<PathBuf> {
+    let mut cmd = ssh_cmd(ssh_uri, "mktemp -d -t om-ci-XXXXXX".to_string());
+    cmd.stderr(Stdio::inherit());
     nix_rs::command::trace_cmd_with("🐌", &cmd);
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Unable to create a temporary directory on {}: {}",
+            ssh_uri,
+            output.status
+        );
+    }
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim(),
+    ))
+}
 
+/// Remove the given directory (see [ssh_mktemp]) on the host
+async fn ssh_rm(ssh_uri: &SSHStoreURI, dir: &Path) -> anyhow::Result<()> {
+    let mut cmd = ssh_cmd(
+        ssh_uri,
+        shell_words::join(["rm", "-rf", &dir.to_string_lossy()]),
+    );
+    nix_rs::command::trace_cmd_with("🐌", &cmd);
     let status = cmd.status().await?;
     if !status.success() {
-        return Err(anyhow::anyhow!("SSH command failed: {}", status));
+        anyhow::bail!("SSH command failed: {}", status);
     }
     Ok(())
 }
 
-/// Run SSH command with given arguments and return the stdout.
-async fn run_ssh_with_output<I, S>(host: &str, args: I) -> anyhow::Result<Vec<u8>>
-where
-    I: IntoIterator<Item = S>,
-    S: AsRef<str>,
-{
-    let mut cmd = Command::new("ssh");
-    cmd.args([host, &shell_words::join(args)]);
+/// Run `om ci run --events -` (see [om_cli_with]) over SSH, returning the path to its results JSON on the remote store, as reported in its `run-finished` event.
+///
+/// The path is returned even if the run failed, as long as the results were written. Its other events are emitted locally, with their subflake suffixed by `label`, if any (as in the merged results), and other lines of its stdout are passed through to stderr, so as not to interleave with the local events stream.
+async fn run_ssh_om(
+    ssh_uri: &SSHStoreURI,
+    label: Option<&str>,
+    args: &[String],
+) -> (Option<PathBuf>, anyhow::Result<()>) {
+    let mut cmd = ssh_cmd(ssh_uri, shell_words::join(args));
+    cmd.stdout(Stdio::piped());
 
     nix_rs::command::trace_cmd_with("🐌", &cmd);
 
-    let output = cmd.output().await?;
-    if output.status.success() {
-        Ok(output.stdout)
-    } else {
-        Err(anyhow::anyhow!(
-            "SSH command failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        ))
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => return (None, Err(err.into())),
+    };
+    let mut result_path = None;
+    if let Some(stdout) = child.stdout.take() {
+        let mut reader = BufReader::new(stdout);
+        let mut buf = vec![];
+        // Read raw bytes, as a line that is not valid UTF-8 must not stop us from reading the `run-finished` event
+        while let Ok(n) = reader.read_until(b'\n', &mut buf).await {
+            if n == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\n', '\r']);
+            match events::parse_line(line) {
+                Some((
+                    _,
+                    Event::RunFinished {
+                        result_path: path, ..
+                    },
+                )) => result_path = path,
+                Some((subflake, event)) => {
+                    let subflake = match label {
+                        Some(label) => subflake.map(|s| format!("{}@{}", s, label)),
+                        None => subflake,
+                    };
+                    events::emit_for(subflake.as_deref(), event);
+                }
+                None => eprintln!("{}", line),
+            }
+            buf.clear();
+        }
     }
+    let res = match child.wait().await {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(anyhow::anyhow!("SSH command failed: {}", status)),
+        Err(err) => Err(err.into()),
+    };
+    (result_path, res)
 }
@@ -0,0 +1,288 @@
+//! The update-lock command
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use colored::Colorize;
+use nix_rs::{
+    config::NixConfig,
+    flake::{
+        command::FlakeOptions,
+        lock::{FlakeLock, LockInput},
+        url::FlakeUrl,
+    },
+    version::NixVersion,
+};
+use omnix_common::config::OmConfig;
+
+use crate::{
+    config::subflakes::SubflakesConfig,
+    github::actions::append_step_summary,
+    update_lock::{to_markdown, InputUpdate, UpdateOutcome},
+};
+
+use super::run::RunCommand;
+
+/// The first Nix version whose `nix flake update` accepts the inputs to update
+const NIX_FLAKE_UPDATE_INPUTS: NixVersion = NixVersion {
+    major: 2,
+    minor: 19,
+    patch: 0,
+};
+
+/// Command to update the flake inputs of all subflakes, and then run CI on them
+#[derive(Parser, Debug, Clone)]
+pub struct UpdateLockCommand {
+    /// Only update these inputs (default: all inputs)
+    #[arg(long = "input", value_name = "NAME")]
+    pub inputs: Vec<String>,
+
+    /// Write the Markdown report of the updates to this path (default: print it to stdout)
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<PathBuf>,
+
+    /// Do not bisect the updates when `om ci run` fails
+    #[arg(long)]
+    pub no_bisect: bool,
+
+    /// The `om ci run` arguments to use
+    #[command(flatten)]
+    pub run_cmd: RunCommand,
+}
+
+/// The `flake.lock` of a subflake, before and after updating it
+struct UpdatedLock {
+    dir: String,
+    path: PathBuf,
+    old: FlakeLock,
+    new: FlakeLock,
+    new_contents: Vec<u8>,
+}
+
+impl UpdateLockCommand {
+    /// Run the command
+    pub async fn run(&self, cfg: OmConfig) -> anyhow::Result<()> {
+        let root = cfg
+            .flake_url
+            .as_local_path()
+            .with_context(|| {
+                format!(
+                    "update-lock requires a local flake, but {} is not",
+                    cfg.flake_url
+                )
+            })?
+            .to_path_buf();
+        let nix_config = NixConfig::get().await.as_ref()?;
+        let systems = self
+            .run_cmd
+            .get_systems(&self.run_cmd.nixcmd, nix_config)
+            .await?;
+        let (config, attrs) = cfg.get_sub_config_under::<SubflakesConfig>("ci")?;
+        let dirs: BTreeSet<String> = config
+            .select(attrs.first(), &systems, None)
+            .filter(|(_, _, skip_reason)| skip_reason.is_none())
+            .map(|(_, subflake, _)| subflake.dir.clone())
+            .collect();
+
+        let mut locks = vec![];
+        for dir in dirs {
+            if let Some(lock) = self.update(&root, &dir).await? {
+                locks.push(lock);
+            }
+        }
+        if locks.is_empty() && !self.inputs.is_empty() {
+            bail!("No subflake has the inputs: {}", self.inputs.join(", "));
+        }
+        let updates: Vec<InputUpdate> = locks
+            .iter()
+            .flat_map(|l| InputUpdate::between(&l.dir, &l.old, &l.new))
+            .collect();
+
+        let res = if updates.is_empty() {
+            tracing::info!("{}", "✅ All inputs are up to date".green());
+            Ok(())
+        } else {
+            self.run_cmd.run(&cfg).await
+        };
+        let outcome = match &res {
+            Ok(()) => UpdateOutcome::Success,
+            Err(_) if updates.len() == 1 => UpdateOutcome::Failure {
+                culprit: Some(Box::new(updates[0].clone())),
+            },
+            Err(_) if self.no_bisect => UpdateOutcome::Failure { culprit: None },
+            Err(_) => {
+                let culprit = self.bisect(&cfg, &locks, &updates).await;
+                // Leave all the inputs updated, as after a successful run
+                for lock in &locks {
+                    std::fs::write(&lock.path, &lock.new_contents)?;
+                }
+                UpdateOutcome::Failure {
+                    culprit: Some(Box::new(culprit?)),
+                }
+            }
+        };
+
+        let report = to_markdown(&updates, &outcome);
+        match &self.report {
+            Some(path) => std::fs::write(path, &report)
+                .with_context(|| format!("Unable to write report to {:?}", path))?,
+            None => print!("{}", report),
+        }
+        if self.run_cmd.github_output {
+            append_step_summary(&report)?;
+        }
+        match outcome {
+            UpdateOutcome::Failure {
+                culprit: Some(culprit),
+            } => res.with_context(|| {
+                format!(
+                    "Updating input '{}' of {} breaks the build",
+                    culprit.input, culprit.dir
+                )
+            }),
+            _ => res,
+        }
+    }
+
+    /// Update the inputs of the flake in `dir`, returning its lock file before and after, if it has any input to update
+    async fn update(&self, root: &Path, dir: &str) -> anyhow::Result<Option<UpdatedLock>> {
+        let path = root.join(dir).join("flake.lock");
+        if !path.exists() {
+            tracing::warn!("Skipping {}, as it has no flake.lock", dir);
+            return Ok(None);
+        }
+        let old = read_lock(&path)?;
+        let inputs: Vec<&String> = old
+            .root_node()
+            .into_iter()
+            .flat_map(|root| &root.inputs)
+            // Inputs following another one are updated along with it
+            .filter(|(_, input)| matches!(input, LockInput::Node(_)))
+            .map(|(name, _)| name)
+            .filter(|name| self.inputs.is_empty() || self.inputs.contains(name))
+            .collect();
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+
+        tracing::info!(
+            "{}",
+            format!("🔄 Updating the inputs of {}/flake.lock", dir).bold()
+        );
+        let url = FlakeUrl::from(root.join(dir).as_path());
+        let nix_version = *NixVersion::get().await.as_ref()?;
+        let res: anyhow::Result<()> = if nix_version >= NIX_FLAKE_UPDATE_INPUTS {
+            let url = url.to_string();
+            let mut cmd = self
+                .run_cmd
+                .nixcmd
+                .command(&["flake", "update", "--flake", &url]);
+            cmd.args(&inputs);
+            nix_rs::command::run_command(&mut cmd)
+                .await
+                .map(|_| ())
+                .map_err(Into::into)
+        } else {
+            // `nix flake lock --update-input` is deprecated, but the only way to update some inputs before Nix 2.19
+            let mut args = vec![];
+            for input in &inputs {
+                args.extend(["--update-input", input.as_str()]);
+            }
+            nix_rs::flake::command::lock(
+                &self.run_cmd.nixcmd,
+                &FlakeOptions::default(),
+                &args,
+                &url,
+            )
+            .await
+            .map_err(Into::into)
+        };
+        res.with_context(|| format!("Unable to update {:?}", path))?;
+
+        let new_contents = std::fs::read(&path)?;
+        let new = read_lock(&path)?;
+        Ok(Some(UpdatedLock {
+            dir: dir.to_string(),
+            path,
+            old,
+            new,
+            new_contents,
+        }))
+    }
+
+    /// Find the update that makes `om ci run` fail, assuming that it succeeded before updating anything.
+    ///
+    /// Runs CI with the first half of the updates applied, and so on, keeping the other inputs as they were.
+    async fn bisect(
+        &self,
+        cfg: &OmConfig,
+        locks: &[UpdatedLock],
+        updates: &[InputUpdate],
+    ) -> anyhow::Result<InputUpdate> {
+        // Do not overwrite the out-link of the actual run
+        let mut run_cmd = self.run_cmd.clone().without_out_link();
+        run_cmd.events = None;
+        run_cmd.junit = None;
+        run_cmd.github_output = false;
+
+        // Applying `good` updates succeeds, and applying `bad` of them fails
+        let (mut good, mut bad) = (0, updates.len());
+        while bad - good > 1 {
+            let mid = (good + bad) / 2;
+            tracing::info!(
+                "{}",
+                format!(
+                    "\n🔎 Bisecting: applying the first {} of {} input updates (up to '{}' of {})",
+                    mid,
+                    updates.len(),
+                    updates[mid - 1].input,
+                    updates[mid - 1].dir
+                )
+                .bold()
+            );
+            apply_updates(locks, &updates[..mid])?;
+            match run_cmd.run(cfg).await {
+                Ok(()) => good = mid,
+                Err(_) => bad = mid,
+            }
+        }
+        let culprit = updates[bad - 1].clone();
+        tracing::info!(
+            "{}",
+            format!(
+                "🔎 Updating '{}' of {} breaks the build",
+                culprit.input, culprit.dir
+            )
+            .red()
+        );
+        Ok(culprit)
+    }
+}
+
+/// Write the lock files with only the given updates applied
+fn apply_updates(locks: &[UpdatedLock], updates: &[InputUpdate]) -> anyhow::Result<()> {
+    let mut by_dir: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for u in updates {
+        by_dir.entry(&u.dir).or_default().push(&u.input);
+    }
+    for lock in locks {
+        let mut partial = lock.old.clone();
+        for input in by_dir.get(lock.dir.as_str()).into_iter().flatten() {
+            partial = partial
+                .with_input_from(input, &lock.new)
+                .with_context(|| format!("Unable to lock input '{}' of {}", input, lock.dir))?;
+        }
+        let mut contents = serde_json::to_string_pretty(&partial)?;
+        contents.push('\n');
+        std::fs::write(&lock.path, contents)?;
+    }
+    Ok(())
+}
+
+fn read_lock(path: &Path) -> anyhow::Result<FlakeLock> {
+    let s = std::fs::read_to_string(path).with_context(|| format!("Unable to read {:?}", path))?;
+    serde_json::from_str(&s).with_context(|| format!("Unable to parse {:?}", path))
+}
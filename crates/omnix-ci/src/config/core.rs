@@ -19,6 +19,6 @@ mod tests {
         let (config, attrs) = cfg.get_sub_config_under::<SubflakesConfig>("ci").unwrap();
         assert_eq!(attrs, &["dev"]);
         // assert_eq!(cfg.selected_subconfig, Some("dev".to_string()));
-        assert_eq!(config.0.len(), 9);
+        assert_eq!(config.subflakes.len(), 9);
     }
 }
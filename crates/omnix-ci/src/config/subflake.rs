@@ -1,8 +1,8 @@
 //! Subflake configuration
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt};
 
 use nix_rs::flake::{system::System, url::FlakeUrl};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::step::core::Steps;
 
@@ -25,6 +25,16 @@ pub struct SubflakeConfig {
     #[serde(rename = "overrideInputs", default)]
     pub override_inputs: BTreeMap<String, FlakeUrl>,
 
+    /// Inputs to override with each of the given flakes in turn
+    ///
+    /// The subflake is run once for every combination of them (see [SubflakeConfig::expand_matrix]).
+    #[serde(rename = "overrideInputsMatrix", default)]
+    pub override_inputs_matrix: BTreeMap<String, Vec<FlakeUrl>>,
+
+    /// Name of the subflake this one was expanded from, if it is a combination of its [SubflakeConfig::override_inputs_matrix]
+    #[serde(skip)]
+    pub matrix_of: Option<String>,
+
     /// An optional whitelist of systems to build on (others are ignored)
     pub systems: Option<Vec<System>>,
 
@@ -40,6 +50,8 @@ impl Default for SubflakeConfig {
             skip: false,
             dir: ".".to_string(),
             override_inputs: BTreeMap::default(),
+            override_inputs_matrix: BTreeMap::default(),
+            matrix_of: None,
             systems: None,
             steps: Steps::default(),
         }
@@ -54,4 +66,63 @@ impl SubflakeConfig {
             None => true,
         }
     }
+
+    /// Expand [SubflakeConfig::override_inputs_matrix] into one subflake per combination of its inputs, named after `name` and the index of each input (e.g. `foo-nixpkgs-0-systems-1`)
+    ///
+    /// The inputs of the matrix take precedence over [SubflakeConfig::override_inputs]. A subflake without matrix is returned as is.
+    pub fn expand_matrix(&self, name: &str) -> Vec<(String, SubflakeConfig)> {
+        if self.override_inputs_matrix.is_empty() {
+            return vec![(name.to_string(), self.clone())];
+        }
+        let mut combinations = vec![(name.to_string(), self.override_inputs.clone())];
+        for (input, urls) in &self.override_inputs_matrix {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|(name, inputs)| {
+                    urls.iter().enumerate().map(move |(i, url)| {
+                        let mut inputs = inputs.clone();
+                        inputs.insert(input.clone(), url.clone());
+                        (format!("{}-{}-{}", name, input, i), inputs)
+                    })
+                })
+                .collect();
+        }
+        combinations
+            .into_iter()
+            .map(|(combination_name, override_inputs)| {
+                let subflake = SubflakeConfig {
+                    override_inputs,
+                    override_inputs_matrix: BTreeMap::new(),
+                    matrix_of: Some(name.to_string()),
+                    ..self.clone()
+                };
+                (combination_name, subflake)
+            })
+            .collect()
+    }
+}
+
+/// Why a subflake is not run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// The user selected some other subflake (e.g. `om ci run .#default.foo`)
+    Deselected,
+    /// [SubflakeConfig::skip] is set
+    Disabled,
+    /// None of the systems being built for are in [SubflakeConfig::systems]
+    UnsupportedSystem,
+    /// Nothing affecting the subflake changed since the ref passed to `--since`
+    Unchanged,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Deselected => write!(f, "deselected out"),
+            SkipReason::Disabled => write!(f, "disabled by `skip`"),
+            SkipReason::UnsupportedSystem => write!(f, "cannot run on this system"),
+            SkipReason::Unchanged => write!(f, "unchanged"),
+        }
+    }
 }
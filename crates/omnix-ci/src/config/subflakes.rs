@@ -1,23 +1,349 @@
 //! Subflakes configuration group.
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, num::NonZeroUsize};
 
+use nix_rs::flake::system::System;
 use serde::Deserialize;
+use serde_json::{Map, Value};
 
-use super::subflake::SubflakeConfig;
+use crate::changes::ChangedFiles;
+
+use super::subflake::{SkipReason, SubflakeConfig};
 
 /// CI configuration for a subflake
 #[derive(Debug, Deserialize, Clone)]
-pub struct SubflakesConfig(
+#[serde(try_from = "Value")]
+pub struct SubflakesConfig {
+    /// Maximum number of subflakes to run concurrently
+    ///
+    /// Overriden by `om ci run --jobs`. Defaults to 1 (i.e., run one subflake at a time).
+    #[serde(default)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// The subflakes, keyed by name
+    ///
+    /// Subflakes with a [SubflakeConfig::override_inputs_matrix] are replaced by their expansion.
     // NB: we use BTreeMap instead of HashMap here so that we always iterate
     // configs in a determinitstic (i.e. asciibetical) order
-    pub BTreeMap<String, SubflakeConfig>,
-);
+    pub subflakes: BTreeMap<String, SubflakeConfig>,
+}
+
+/// [SubflakesConfig] as declared by the user, once [effective_config] is applied
+#[derive(Deserialize)]
+struct DeclaredSubflakesConfig {
+    #[serde(default)]
+    jobs: Option<NonZeroUsize>,
+    #[serde(flatten)]
+    subflakes: BTreeMap<String, SubflakeConfig>,
+}
+
+impl TryFrom<DeclaredSubflakesConfig> for SubflakesConfig {
+    type Error = String;
+
+    fn try_from(declared: DeclaredSubflakesConfig) -> Result<Self, Self::Error> {
+        let mut subflakes = BTreeMap::new();
+        for (name, subflake) in &declared.subflakes {
+            if let Some((input, _)) = subflake
+                .override_inputs_matrix
+                .iter()
+                .find(|(_, urls)| urls.is_empty())
+            {
+                return Err(format!(
+                    "overrideInputsMatrix.{} of subflake '{}' is empty",
+                    input, name
+                ));
+            }
+            for (name, subflake) in subflake.expand_matrix(name) {
+                let conflicts = subflakes.contains_key(&name)
+                    || (subflake.matrix_of.is_some() && declared.subflakes.contains_key(&name));
+                if conflicts {
+                    return Err(format!(
+                        "Subflake '{}' conflicts with an expansion of overrideInputsMatrix",
+                        name
+                    ));
+                }
+                subflakes.insert(name, subflake);
+            }
+        }
+        Ok(SubflakesConfig {
+            jobs: declared.jobs,
+            subflakes,
+        })
+    }
+}
+
+impl TryFrom<Value> for SubflakesConfig {
+    type Error = String;
+
+    fn try_from(declared: Value) -> Result<Self, Self::Error> {
+        let declared: DeclaredSubflakesConfig =
+            serde_json::from_value(effective_config(declared)?).map_err(|e| e.to_string())?;
+        declared.try_into()
+    }
+}
+
+/// Keys of the CI configuration that hold settings, and thus cannot name a subflake
+pub const RESERVED_NAMES: [&str; 2] = ["defaults", "jobs"];
+
+/// Resolve the `defaults` and `extends` of a (JSON) CI configuration, returning it with every subflake as it is in effect
+///
+/// Each subflake is deep-merged into the subflake it `extends`, or else into `defaults`: objects are merged key by key, while any other value of the subflake (including lists) replaces the inherited one.
+pub fn effective_config(declared: Value) -> Result<Value, String> {
+    let Value::Object(mut declared) = declared else {
+        return Ok(declared);
+    };
+    let defaults = match declared.remove("defaults") {
+        None => Value::Object(Map::new()),
+        Some(defaults @ Value::Object(_)) => defaults,
+        Some(_) => return Err("`defaults` must be an object".to_string()),
+    };
+    if declared.get("jobs").is_some_and(Value::is_object) {
+        return Err(format!(
+            "`jobs` must be a number; note that {} cannot name a subflake",
+            RESERVED_NAMES
+                .map(|name| format!("`{}`", name))
+                .join(" and ")
+        ));
+    }
+    let mut effective = Map::new();
+    for (name, value) in &declared {
+        let value = if RESERVED_NAMES.contains(&name.as_str()) {
+            value.clone()
+        } else {
+            resolve_subflake(&declared, &defaults, name, &mut vec![])?
+        };
+        effective.insert(name.clone(), value);
+    }
+    Ok(Value::Object(effective))
+}
+
+/// The subflake `name` of `declared`, merged into what it inherits
+///
+/// `extending` holds the subflakes whose `extends` led to this one, to detect cycles.
+fn resolve_subflake<'a>(
+    declared: &'a Map<String, Value>,
+    defaults: &Value,
+    name: &'a str,
+    extending: &mut Vec<&'a str>,
+) -> Result<Value, String> {
+    let Some(Value::Object(subflake)) = declared.get(name) else {
+        // Let deserialization report it
+        return Ok(declared.get(name).cloned().unwrap_or_default());
+    };
+    let mut own = subflake.clone();
+    let mut base = match own.remove("extends") {
+        None => defaults.clone(),
+        Some(Value::String(parent)) => {
+            if extending.contains(&name) {
+                return Err(format!(
+                    "Cyclic `extends`: {} -> {}",
+                    extending.join(" -> "),
+                    name
+                ));
+            }
+            extending.push(name);
+            let (parent, _) = declared.get_key_value(&parent).ok_or_else(|| {
+                format!("Subflake '{}' extends unknown subflake '{}'", name, parent)
+            })?;
+            resolve_subflake(declared, defaults, parent, extending)?
+        }
+        Some(_) => return Err(format!("`extends` of subflake '{}' must be a string", name)),
+    };
+    deep_merge(&mut base, Value::Object(own));
+    Ok(base)
+}
+
+/// Merge `overlay` into `base`, recursively for objects
+fn deep_merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => deep_merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
 
 impl Default for SubflakesConfig {
     /// Default value contains a single entry for the root flake.
     fn default() -> Self {
         let mut subflakes = BTreeMap::new();
         subflakes.insert("ROOT".to_string(), SubflakeConfig::default());
-        SubflakesConfig(subflakes)
+        SubflakesConfig {
+            jobs: None,
+            subflakes,
+        }
+    }
+}
+
+impl SubflakesConfig {
+    /// Iterate over all subflakes, along with the reason for not running them (if any).
+    ///
+    /// `only_subflake` is the subflake explicitly selected by the user, if any (selecting a subflake with an `overrideInputsMatrix` selects all of its combinations).
+    /// If `changes` is given, subflakes not affected by them are skipped.
+    pub fn select<'a>(
+        &'a self,
+        only_subflake: Option<&'a String>,
+        systems: &'a [System],
+        changes: Option<&'a ChangedFiles>,
+    ) -> impl Iterator<Item = (&'a String, &'a SubflakeConfig, Option<SkipReason>)> {
+        self.subflakes.iter().map(move |(name, subflake)| {
+            let selected = |s: &String| s == name || subflake.matrix_of.as_ref() == Some(s);
+            let skip_reason = if only_subflake.is_some_and(|s| !selected(s)) {
+                Some(SkipReason::Deselected)
+            } else if subflake.skip {
+                Some(SkipReason::Disabled)
+            } else if !subflake.can_run_on(systems) {
+                Some(SkipReason::UnsupportedSystem)
+            } else if changes.is_some_and(|c| !c.affects(subflake)) {
+                Some(SkipReason::Unchanged)
+            } else {
+                None
+            };
+            (name, subflake, skip_reason)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jobs_alongside_subflakes() {
+        let config: SubflakesConfig = serde_json::from_value(serde_json::json!({
+            "jobs": 4,
+            "foo": { "dir": "foo" },
+            "bar": { "dir": "bar", "skip": true },
+        }))
+        .unwrap();
+        assert_eq!(config.jobs, NonZeroUsize::new(4));
+        assert_eq!(
+            config.subflakes.keys().collect::<Vec<_>>(),
+            vec!["bar", "foo"]
+        );
+    }
+
+    #[test]
+    fn test_reserved_name() {
+        let err = SubflakesConfig::try_from(serde_json::json!({
+            "jobs": { "dir": "jobs" },
+        }))
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "`jobs` must be a number; note that `defaults` and `jobs` cannot name a subflake"
+        );
+    }
+
+    #[test]
+    fn test_override_inputs_matrix() {
+        let config: SubflakesConfig = serde_json::from_value(serde_json::json!({
+            "lib": {
+                "dir": "lib",
+                "overrideInputs": { "nixpkgs": "github:nixos/nixpkgs", "lib": "." },
+                "overrideInputsMatrix": {
+                    "nixpkgs": ["github:nixos/nixpkgs/nixos-24.05", "github:nixos/nixpkgs/nixos-unstable"],
+                    "systems": ["github:nix-systems/default"],
+                },
+            },
+            "doc": { "dir": "doc" },
+        }))
+        .unwrap();
+        assert_eq!(
+            config.subflakes.keys().collect::<Vec<_>>(),
+            vec!["doc", "lib-nixpkgs-0-systems-0", "lib-nixpkgs-1-systems-0"]
+        );
+        let lib = &config.subflakes["lib-nixpkgs-1-systems-0"];
+        assert_eq!(lib.matrix_of.as_deref(), Some("lib"));
+        assert_eq!(
+            lib.override_inputs
+                .values()
+                .map(|u| u.0.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                ".",
+                "github:nixos/nixpkgs/nixos-unstable",
+                "github:nix-systems/default"
+            ]
+        );
+
+        let only = "lib".to_string();
+        let selected: Vec<&String> = config
+            .select(Some(&only), &[], None)
+            .filter(|(_, _, skip_reason)| skip_reason.is_none())
+            .map(|(name, _, _)| name)
+            .collect();
+        assert_eq!(
+            selected,
+            vec!["lib-nixpkgs-0-systems-0", "lib-nixpkgs-1-systems-0"]
+        );
+
+        let empty = serde_json::from_value::<SubflakesConfig>(serde_json::json!({
+            "lib": { "dir": "lib", "overrideInputsMatrix": { "nixpkgs": [] } },
+        }));
+        assert!(empty.is_err());
+    }
+
+    #[test]
+    fn test_defaults_and_extends() {
+        let declared = serde_json::json!({
+            "jobs": 2,
+            "defaults": {
+                "systems": ["x86_64-linux"],
+                "steps": { "flake-check": { "enable": true }, "build": { "enable": true, "impure": true } },
+            },
+            "base": {
+                "dir": ".",
+                "skip": true,
+                "steps": { "build": { "impure": false } },
+            },
+            "foo": { "dir": "foo", "steps": { "flake-check": { "enable": false } } },
+            "bar": { "extends": "base", "skip": false, "systems": ["aarch64-darwin"] },
+        });
+        let effective = effective_config(declared.clone()).unwrap();
+        assert_eq!(
+            effective,
+            serde_json::json!({
+                "jobs": 2,
+                "base": {
+                    "dir": ".",
+                    "skip": true,
+                    "systems": ["x86_64-linux"],
+                    "steps": { "flake-check": { "enable": true }, "build": { "enable": true, "impure": false } },
+                },
+                "foo": {
+                    "dir": "foo",
+                    "systems": ["x86_64-linux"],
+                    "steps": { "flake-check": { "enable": false }, "build": { "enable": true, "impure": true } },
+                },
+                "bar": {
+                    "dir": ".",
+                    "skip": false,
+                    "systems": ["aarch64-darwin"],
+                    "steps": { "flake-check": { "enable": true }, "build": { "enable": true, "impure": false } },
+                },
+            })
+        );
+        let config: SubflakesConfig = serde_json::from_value(declared).unwrap();
+        assert_eq!(config.jobs, NonZeroUsize::new(2));
+        assert_eq!(
+            config.subflakes.keys().collect::<Vec<_>>(),
+            vec!["bar", "base", "foo"]
+        );
+        assert!(config.subflakes["bar"].steps.flake_check_step.enable);
+
+        let cyclic = serde_json::json!({
+            "a": { "dir": "a", "extends": "b" },
+            "b": { "dir": "b", "extends": "a" },
+        });
+        assert!(effective_config(cyclic).unwrap_err().contains("Cyclic"));
+        let unknown = serde_json::json!({ "a": { "dir": "a", "extends": "b" } });
+        assert!(effective_config(unknown).is_err());
     }
 }
@@ -0,0 +1,247 @@
+//! Machine-readable stream of `om ci run` lifecycle events (`--events`)
+//!
+//! Each event is written as a single line of JSON (i.e., [NDJSON](https://github.com/ndjson/ndjson-spec)),
+//! wrapped in an [EventLine] that carries the [SCHEMA_VERSION]. Events are only
+//! written if [init] was called; otherwise [emit] does nothing.
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use nix_rs::{
+    flake::{system::System, url::FlakeUrl},
+    store::path::StorePath,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::subflake::SkipReason, step::core::StepOutcome};
+
+/// Version of the event schema
+///
+/// Bumped whenever an event or field is removed or changes meaning. Adding new
+/// events or fields is not a breaking change; consumers should ignore those
+/// they do not know about.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A lifecycle event of `om ci run`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    tag = "event",
+    rename_all = "kebab-case",
+    rename_all_fields = "camelCase"
+)]
+pub enum Event {
+    /// CI is about to run on the given flake
+    RunStarted {
+        /// The flake being built
+        flake: FlakeUrl,
+        /// The systems being built for
+        systems: Vec<System>,
+    },
+    /// A subflake was selected to be run
+    SubflakeSelected,
+    /// A subflake will not be run
+    SubflakeSkipped {
+        /// Why it will not be run
+        reason: SkipReason,
+    },
+    /// A step started running
+    StepStarted {
+        /// Name of the step (custom steps are named `custom.<name>`)
+        step: String,
+    },
+    /// A step finished running (or was skipped)
+    StepFinished {
+        /// Its outcome, as recorded in the results JSON
+        #[serde(flatten)]
+        outcome: StepOutcome,
+    },
+    /// The build step produced these store paths
+    StorePaths {
+        /// The built store paths
+        paths: Vec<StorePath>,
+    },
+    /// CI finished running
+    RunFinished {
+        /// Whether all steps succeeded
+        success: bool,
+        /// Path to the results JSON, if it was written
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result_path: Option<PathBuf>,
+        /// The error that stopped the run, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// An [Event], as written to the events stream
+#[derive(Debug, Clone, Serialize)]
+pub struct EventLine<'a> {
+    /// See [SCHEMA_VERSION]
+    pub version: u32,
+    /// When the event happened
+    pub timestamp: DateTime<Utc>,
+    /// The subflake the event is about, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subflake: Option<&'a str>,
+    /// The event
+    #[serde(flatten)]
+    pub event: &'a Event,
+}
+
+static SINK: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// Whether [SINK] is stdout
+static TO_STDOUT: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static SUBFLAKE: String;
+}
+
+/// Write events to the given path (`-` for stdout) from now on
+pub fn init(path: &str) -> anyhow::Result<()> {
+    let writer: Box<dyn Write + Send> = if path == "-" {
+        TO_STDOUT.store(true, Ordering::Relaxed);
+        Box::new(std::io::stdout())
+    } else {
+        let file = File::create(path).with_context(|| format!("Unable to create {:?}", path))?;
+        Box::new(LineWriter::new(file))
+    };
+    SINK.set(Mutex::new(writer))
+        .map_err(|_| anyhow::anyhow!("Events stream was already initialized"))
+}
+
+/// Send the stdout of the given command to stderr if events are written to stdout, so as to not corrupt their stream
+///
+/// This is needed for the commands whose output is not captured, such as those of custom steps.
+pub fn keep_stdout_clear(cmd: &mut tokio::process::Command) {
+    if TO_STDOUT.load(Ordering::Relaxed) {
+        cmd.stdout(std::io::stderr());
+    }
+}
+
+/// Run the given future, such that the events it emits are about the given subflake
+pub async fn in_subflake<F>(subflake: String, f: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    SUBFLAKE.scope(subflake, f).await
+}
+
+/// Parse a line of the events stream, if it is one (with a known event), returning the event along with the subflake it is about
+///
+/// This is how the events of `om ci run` on a remote (including its results path) are retrieved.
+pub fn parse_line(line: &str) -> Option<(Option<String>, Event)> {
+    #[derive(Deserialize)]
+    struct Line {
+        version: u32,
+        subflake: Option<String>,
+        #[serde(flatten)]
+        event: Event,
+    }
+    let line: Line = serde_json::from_str(line).ok()?;
+    (line.version == SCHEMA_VERSION).then_some((line.subflake, line.event))
+}
+
+/// Emit an event about the current subflake (see [in_subflake]), if any
+pub fn emit(event: Event) {
+    let subflake = SUBFLAKE.try_with(|s| s.clone()).ok();
+    emit_for(subflake.as_deref(), event);
+}
+
+/// Emit an event about the given subflake
+pub fn emit_for(subflake: Option<&str>, event: Event) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let line = EventLine {
+        version: SCHEMA_VERSION,
+        timestamp: Utc::now(),
+        subflake,
+        event: &event,
+    };
+    let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+    let res = serde_json::to_writer(&mut *sink, &line)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writeln!(sink))
+        .and_then(|()| sink.flush());
+    if let Err(err) = res {
+        tracing::warn!("Unable to write event: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::step::core::StepStatus;
+
+    #[test]
+    fn test_event_line_schema() {
+        let event = Event::StepFinished {
+            outcome: StepOutcome {
+                name: "build".to_string(),
+                status: StepStatus::Failure {
+                    error: "boom".to_string(),
+                },
+                duration: 1.5,
+                started_at: None,
+                finished_at: None,
+            },
+        };
+        let line = EventLine {
+            version: SCHEMA_VERSION,
+            timestamp: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            subflake: Some("ROOT"),
+            event: &event,
+        };
+        assert_eq!(
+            serde_json::to_value(&line).unwrap(),
+            serde_json::json!({
+                "version": 1,
+                "timestamp": "2023-11-14T22:13:20Z",
+                "subflake": "ROOT",
+                "event": "step-finished",
+                "name": "build",
+                "status": "failure",
+                "error": "boom",
+                "duration": 1.5,
+            })
+        );
+
+        let event = Event::RunFinished {
+            success: true,
+            result_path: Some(PathBuf::from("/nix/store/abc-om-ci-results.json")),
+            error: None,
+        };
+        let line = EventLine {
+            subflake: None,
+            event: &event,
+            ..line
+        };
+        assert_eq!(
+            serde_json::to_value(&line).unwrap(),
+            serde_json::json!({
+                "version": 1,
+                "timestamp": "2023-11-14T22:13:20Z",
+                "event": "run-finished",
+                "success": true,
+                "resultPath": "/nix/store/abc-om-ci-results.json",
+            })
+        );
+        let parsed = parse_line(&serde_json::to_string(&line).unwrap());
+        assert!(matches!(
+            parsed,
+            Some((None, Event::RunFinished { result_path: Some(p), .. })) if p == Path::new("/nix/store/abc-om-ci-results.json")
+        ));
+        assert!(parse_line("/nix/store/abc-om-ci-results.json").is_none());
+    }
+}
@@ -1,6 +1,10 @@
 //! Working with GitHub Actions
 
-use std::future::Future;
+use std::{env, fs::OpenOptions, future::Future, io::Write};
+
+use anyhow::Context;
+
+use crate::ansi::strip_ansi;
 
 /// Group log lines in GitHub Actions
 ///
@@ -22,3 +26,63 @@ where
 
     result
 }
+
+/// Create an error annotation in GitHub Actions
+///
+/// https://docs.github.com/en/actions/writing-workflows/choosing-what-your-workflow-does/workflow-commands-for-github-actions#setting-an-error-message
+pub fn error_annotation(title: &str, message: &str) {
+    eprintln!(
+        "::error title={}::{}",
+        escape_property(title),
+        escape_data(&strip_ansi(message))
+    );
+}
+
+/// Append Markdown to the summary of the job (`$GITHUB_STEP_SUMMARY`), if running in GitHub Actions
+///
+/// https://docs.github.com/en/actions/writing-workflows/choosing-what-your-workflow-does/workflow-commands-for-github-actions#adding-a-job-summary
+pub fn append_step_summary(markdown: &str) -> anyhow::Result<()> {
+    append_to_env_file("GITHUB_STEP_SUMMARY", markdown)
+}
+
+/// Set an output parameter of the step (in `$GITHUB_OUTPUT`), if running in GitHub Actions
+///
+/// https://docs.github.com/en/actions/writing-workflows/choosing-what-your-workflow-does/workflow-commands-for-github-actions#setting-an-output-parameter
+pub fn set_output(name: &str, value: &str) -> anyhow::Result<()> {
+    append_to_env_file("GITHUB_OUTPUT", &format!("{}={}\n", name, value))
+}
+
+/// Append `contents` to the file named by the environment variable `var`, if it is set
+fn append_to_env_file(var: &str, contents: &str) -> anyhow::Result<()> {
+    let Some(path) = env::var_os(var) else {
+        tracing::debug!("${} is not set; not writing to it", var);
+        return Ok(());
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .with_context(|| format!("Unable to write to ${} ({:?})", var, path))
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_data("100% done\nok"), "100%25 done%0Aok");
+        assert_eq!(escape_property("ROOT: build, x"), "ROOT%3A build%2C x");
+    }
+}
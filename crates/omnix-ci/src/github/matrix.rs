@@ -2,7 +2,7 @@
 use nix_rs::flake::system::System;
 use serde::{Deserialize, Serialize};
 
-use crate::config::subflakes::SubflakesConfig;
+use crate::{changes::ChangedFiles, config::subflakes::SubflakesConfig};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 /// A row in the Github Actions matrix configuration
@@ -22,15 +22,20 @@ pub struct GitHubMatrix {
 
 impl GitHubMatrix {
     /// Create a [GitHubMatrix] for the given subflakes and systems
-    pub fn from(systems: Vec<System>, subflakes: &SubflakesConfig) -> Self {
+    ///
+    /// If `changes` is given, only the subflakes affected by them are included.
+    pub fn from(
+        systems: Vec<System>,
+        subflakes: &SubflakesConfig,
+        changes: Option<&ChangedFiles>,
+    ) -> Self {
         let include: Vec<GitHubMatrixRow> = systems
             .iter()
             .flat_map(|system| {
                 subflakes
-                    .0
-                    .iter()
-                    .filter(|&(_k, v)| v.can_run_on(std::slice::from_ref(system)))
-                    .map(|(k, _v)| GitHubMatrixRow {
+                    .select(None, std::slice::from_ref(system), changes)
+                    .filter(|(_k, _v, skip_reason)| skip_reason.is_none())
+                    .map(|(k, _v, _)| GitHubMatrixRow {
                         system: system.clone(),
                         subflake: k.clone(),
                     })
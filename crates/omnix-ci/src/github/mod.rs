@@ -2,3 +2,4 @@
 pub mod actions;
 pub mod matrix;
 pub mod pull_request;
+pub mod summary;
@@ -0,0 +1,151 @@
+//! Markdown summary of `om ci run`, for `$GITHUB_STEP_SUMMARY`
+use std::fmt::Write;
+
+use crate::{
+    ansi::strip_ansi,
+    command::run::RunResult,
+    step::core::{StepOutcome, StepStatus},
+};
+
+/// Render the given [RunResult] as Markdown
+///
+/// Lists the outcome and duration of every step, and the number of outputs built by each subflake.
+pub fn to_markdown(res: &RunResult) -> String {
+    let mut md = String::new();
+    let icon = if res.failures().next().is_none() {
+        "✅"
+    } else {
+        "❌"
+    };
+    writeln!(md, "### {} om ci run `{}`\n", icon, res.flake).unwrap();
+    let systems = res
+        .systems
+        .iter()
+        .map(|s| format!("`{}`", s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    writeln!(md, "Systems: {}\n", systems).unwrap();
+
+    if !res.result.is_empty() {
+        writeln!(md, "| Subflake | Step | Status | Duration |").unwrap();
+        writeln!(md, "| --- | --- | --- | --- |").unwrap();
+        for (subflake, steps_res) in &res.result {
+            for step in &steps_res.steps {
+                writeln!(
+                    md,
+                    "| {} | {} | {} | {} |",
+                    cell(subflake),
+                    cell(&step.name),
+                    status(step),
+                    duration(step)
+                )
+                .unwrap();
+            }
+        }
+        writeln!(md).unwrap();
+
+        writeln!(md, "| Subflake | Outputs |").unwrap();
+        writeln!(md, "| --- | --- |").unwrap();
+        for (subflake, steps_res) in &res.result {
+            let outputs = steps_res.build_step.as_ref().map_or("-".to_string(), |b| {
+                b.devour_flake_output.out_paths.len().to_string()
+            });
+            writeln!(md, "| {} | {} |", cell(subflake), outputs).unwrap();
+        }
+        writeln!(md).unwrap();
+    }
+
+    if !res.skipped.is_empty() {
+        let skipped = res
+            .skipped
+            .iter()
+            .map(|(name, reason)| format!("{} ({})", cell(name), reason))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(md, "Skipped subflakes: {}\n", skipped).unwrap();
+    }
+    md
+}
+
+fn status(step: &StepOutcome) -> String {
+    match &step.status {
+        StepStatus::Success => "✅ success".to_string(),
+        StepStatus::Failure { error } => {
+            let error = strip_ansi(error.lines().next().unwrap_or_default());
+            format!("❌ failed: {}", error.replace('|', "\\|"))
+        }
+        StepStatus::Skipped { reason } => format!("⏭️ skipped ({})", reason),
+    }
+}
+
+fn duration(step: &StepOutcome) -> String {
+    match step.status {
+        StepStatus::Skipped { .. } => String::new(),
+        _ => format!("{:.1}s", step.duration),
+    }
+}
+
+/// Format a name as inline code in a table cell
+fn cell(s: &str) -> String {
+    format!("`{}`", s.replace('|', "\\|"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use chrono::Utc;
+    use nix_rs::flake::url::FlakeUrl;
+
+    use crate::{
+        config::subflake::SkipReason,
+        step::core::{StepTiming, StepsResult},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_to_markdown() {
+        let timing = StepTiming {
+            started_at: Utc::now(),
+            duration: Duration::from_millis(2500),
+        };
+        let mut steps_res = StepsResult::default();
+        steps_res
+            .record::<()>(
+                "flake-check",
+                Err(anyhow::anyhow!("\x1b[31merror:\x1b[0m boom\nmore")),
+                timing,
+                true,
+            )
+            .unwrap();
+        steps_res.record_skipped("cache", "disabled");
+        let res = RunResult {
+            systems: vec!["x86_64-linux".into()],
+            flake: FlakeUrl("github:juspay/omnix".to_string()),
+            result: BTreeMap::from([("ROOT".to_string(), steps_res)]),
+            skipped: BTreeMap::from([("doc".to_string(), SkipReason::Unchanged)]),
+            metadata: None,
+            runs: BTreeMap::new(),
+        };
+        assert_eq!(
+            to_markdown(&res),
+            r#"### ❌ om ci run `github:juspay/omnix`
+
+Systems: `x86_64-linux`
+
+| Subflake | Step | Status | Duration |
+| --- | --- | --- | --- |
+| `ROOT` | `flake-check` | ❌ failed: error: boom | 2.5s |
+| `ROOT` | `cache` | ⏭️ skipped (disabled) |  |
+
+| Subflake | Outputs |
+| --- | --- |
+| `ROOT` | - |
+
+Skipped subflakes: `doc` (unchanged)
+
+"#
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! JUnit XML reports of `om ci run`
+//!
+//! Each subflake is reported as a `<testsuite>`, and each of its steps as a `<testcase>`.
+//!
+//! See <https://github.com/testmoapp/junitxml> for the format.
+use std::fmt::Write;
+
+use crate::{
+    ansi::strip_ansi,
+    command::run::RunResult,
+    step::core::{StepStatus, StepsResult},
+};
+
+/// Render the given [RunResult] as a JUnit XML document
+pub fn to_junit_xml(res: &RunResult) -> String {
+    let mut xml = String::new();
+    let totals = Totals::of(res.result.values());
+    writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        xml,
+        r#"<testsuites name="{}" {}>"#,
+        escape(&format!("om ci run {}", res.flake)),
+        totals.attrs()
+    )
+    .unwrap();
+    for (subflake, steps_res) in &res.result {
+        write_testsuite(&mut xml, subflake, steps_res);
+    }
+    writeln!(xml, "</testsuites>").unwrap();
+    xml
+}
+
+fn write_testsuite(xml: &mut String, subflake: &str, steps_res: &StepsResult) {
+    let subflake = escape(subflake);
+    let totals = Totals::of([steps_res]);
+    writeln!(
+        xml,
+        r#"  <testsuite name="{}" {}>"#,
+        subflake,
+        totals.attrs()
+    )
+    .unwrap();
+    for step in &steps_res.steps {
+        write!(
+            xml,
+            r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+            escape(&step.name),
+            subflake,
+            step.duration
+        )
+        .unwrap();
+        match &step.status {
+            StepStatus::Success => writeln!(xml, "/>").unwrap(),
+            StepStatus::Failure { error } => {
+                writeln!(xml, ">").unwrap();
+                writeln!(
+                    xml,
+                    r#"      <failure message="{}">{}</failure>"#,
+                    escape(error.lines().next().unwrap_or_default()),
+                    escape(error)
+                )
+                .unwrap();
+                writeln!(xml, "    </testcase>").unwrap();
+            }
+            StepStatus::Skipped { reason } => {
+                writeln!(xml, ">").unwrap();
+                writeln!(xml, r#"      <skipped message="{}"/>"#, escape(reason)).unwrap();
+                writeln!(xml, "    </testcase>").unwrap();
+            }
+        }
+    }
+    writeln!(xml, "  </testsuite>").unwrap();
+}
+
+/// Aggregate counts for a `<testsuite>` or `<testsuites>` element
+#[derive(Default)]
+struct Totals {
+    tests: usize,
+    failures: usize,
+    skipped: usize,
+    time: f64,
+}
+
+impl Totals {
+    fn of<'a>(results: impl IntoIterator<Item = &'a StepsResult>) -> Self {
+        let mut totals = Totals::default();
+        for step in results.into_iter().flat_map(|res| &res.steps) {
+            totals.tests += 1;
+            totals.time += step.duration;
+            match step.status {
+                StepStatus::Success => {}
+                StepStatus::Failure { .. } => totals.failures += 1,
+                StepStatus::Skipped { .. } => totals.skipped += 1,
+            }
+        }
+        totals
+    }
+
+    fn attrs(&self) -> String {
+        format!(
+            r#"tests="{}" failures="{}" errors="0" skipped="{}" time="{:.3}""#,
+            self.tests, self.failures, self.skipped, self.time
+        )
+    }
+}
+
+/// Escape a string for use in XML text and attribute values.
+///
+/// ANSI escape sequences (found in Nix's error messages) and other characters
+/// not allowed in XML 1.0 are dropped.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in strip_ansi(s).chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use chrono::Utc;
+    use nix_rs::flake::url::FlakeUrl;
+
+    use crate::step::core::StepTiming;
+
+    use super::*;
+
+    fn timing(duration: Duration) -> StepTiming {
+        StepTiming {
+            started_at: Utc::now(),
+            duration,
+        }
+    }
+
+    #[test]
+    fn test_to_junit_xml() {
+        let mut steps_res = StepsResult::default();
+        steps_res
+            .record("build", Ok(()), timing(Duration::from_millis(1500)), true)
+            .unwrap();
+        steps_res
+            .record::<()>(
+                "custom.test",
+                Err(anyhow::anyhow!("\x1b[31merror:\x1b[0m <boom>")),
+                timing(Duration::from_secs(2)),
+                true,
+            )
+            .unwrap();
+        steps_res.record_skipped("flake-check", "disabled");
+        let res = RunResult {
+            systems: vec![],
+            flake: FlakeUrl("github:juspay/omnix".to_string()),
+            result: BTreeMap::from([("omnix".to_string(), steps_res)]),
+            skipped: BTreeMap::new(),
+            metadata: None,
+            runs: BTreeMap::new(),
+        };
+        assert_eq!(
+            to_junit_xml(&res),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="om ci run github:juspay/omnix" tests="3" failures="1" errors="0" skipped="1" time="3.500">
+  <testsuite name="omnix" tests="3" failures="1" errors="0" skipped="1" time="3.500">
+    <testcase name="build" classname="omnix" time="1.500"/>
+    <testcase name="custom.test" classname="omnix" time="2.000">
+      <failure message="error: &lt;boom&gt;">error: &lt;boom&gt;</failure>
+    </testcase>
+    <testcase name="flake-check" classname="omnix" time="0.000">
+      <skipped message="disabled"/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+}
@@ -1,8 +1,15 @@
 //! omnix-ci: CI for Nix projects
 #![warn(missing_docs)]
+pub mod ansi;
+pub mod changes;
 pub mod command;
 pub mod config;
+pub mod events;
 pub mod flake_ref;
 pub mod github;
+pub mod junit;
 pub mod nix;
+pub mod pipeline;
+pub mod provenance;
 pub mod step;
+pub mod update_lock;
@@ -0,0 +1,49 @@
+# A flake whose outputs are those of the `flake` input, filtered by attribute path.
+#
+# Generated by `om ci run` for the `include` and `exclude` options of the build
+# step (see filter_flake.rs), alongside `filters.json`, which has a list of
+# globs for each option. Each glob is a list of regexes, one per attribute name.
+{
+  inputs.flake.url = "@FLAKE_URL@";
+  outputs = { flake, ... }:
+    let
+      filters = builtins.fromJSON (builtins.readFile ./filters.json);
+      take = n: l: builtins.genList (builtins.elemAt l) n;
+      matches = glob: path:
+        builtins.length glob == builtins.length path
+        && builtins.all (i: builtins.match (builtins.elemAt glob i) (builtins.elemAt path i) != null)
+          (builtins.genList (i: i) (builtins.length path));
+      # Whether `glob` can match attributes under `path`
+      matchesUnder = path: glob:
+        builtins.length glob > builtins.length path
+        && matches (take (builtins.length path) glob) path;
+      isDerivation = x: (x.type or null) == "derivation";
+
+      # Filter `value`, found at `path`. `included` is whether an include glob
+      # matched one of its parents. Only recurses as deep as the globs go.
+      go = included: path: value:
+        if builtins.any (g: matches g path) filters.exclude then
+          { keep = false; filtered = [ path ]; }
+        else
+          let
+            included' = included || filters.include == [ ] || builtins.any (g: matches g path) filters.include;
+            descend = builtins.isAttrs value && !(isDerivation value)
+              && builtins.any (matchesUnder path) (filters.exclude ++ (if included' then [ ] else filters.include));
+            children = builtins.mapAttrs (name: go included' (path ++ [ name ])) value;
+            kept = builtins.filter (name: children.${name}.keep) (builtins.attrNames children);
+          in
+          if descend then {
+            keep = true;
+            value = builtins.listToAttrs (map (name: { inherit name; inherit (children.${name}) value; }) kept);
+            filtered = builtins.concatMap (c: c.filtered) (builtins.attrValues children);
+          }
+          else if included' then { keep = true; inherit value; filtered = [ ]; }
+          else { keep = false; filtered = [ path ]; };
+
+      root = go false [ ] flake.outputs;
+    in
+    root.value // {
+      # Not a standard flake output; read by `om ci run` to report what was filtered out.
+      omnixFiltered = map (builtins.concatStringsSep ".") root.filtered;
+    };
+}
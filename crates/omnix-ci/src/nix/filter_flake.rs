@@ -0,0 +1,214 @@
+//! Filter the outputs of a flake by attribute path, for the `include` and `exclude` options of the build step
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use nix_rs::{
+    command::NixCmd,
+    flake::{command::FlakeOptions, eval::nix_eval, url::FlakeUrl},
+};
+use serde::Serialize;
+use tempfile::TempDir;
+
+/// Template of the wrapper flake's `flake.nix`
+const FLAKE_NIX: &str = include_str!("filter-flake.nix");
+
+/// A (temporary) flake whose outputs are those of another flake, filtered by attribute path globs
+///
+/// A glob is an attribute path whose names may contain `*` (any characters)
+/// and `?` (any one character), e.g. `packages.*.docs`. An attribute is
+/// filtered out if it matches an `exclude` glob, or if there are `include`
+/// globs and neither it nor any of its parents match one.
+pub struct FilteredFlake {
+    /// Holds the wrapper flake; removed on drop
+    dir: TempDir,
+}
+
+#[derive(Serialize)]
+struct Filters {
+    include: Vec<Vec<String>>,
+    exclude: Vec<Vec<String>>,
+}
+
+impl FilteredFlake {
+    /// Create a flake that wraps `flake`, filtering its outputs by the given globs
+    pub fn new(flake: &FlakeUrl, include: &[String], exclude: &[String]) -> anyhow::Result<Self> {
+        // Relative paths would be resolved relative to the wrapper flake. The
+        // rest of the URL is kept as is: whether it has the `path:` scheme
+        // determines which files Nix sees (as opposed to `git+file:`).
+        let flake = match flake.as_local_path() {
+            Some(path) if path.is_relative() => {
+                let scheme = if flake.0.starts_with("path:") {
+                    "path:"
+                } else {
+                    ""
+                };
+                let rest = &flake.0[scheme.len() + path.as_os_str().len()..];
+                FlakeUrl(format!(
+                    "{}{}{}",
+                    scheme,
+                    std::path::absolute(path)?.display(),
+                    rest
+                ))
+            }
+            _ => flake.clone(),
+        };
+        let filters = Filters {
+            include: include.iter().map(|g| glob_to_regexes(g)).collect(),
+            exclude: exclude.iter().map(|g| glob_to_regexes(g)).collect(),
+        };
+        let dir = tempfile::Builder::new().prefix("om-ci-filter-").tempdir()?;
+        let flake_url = serde_json::to_string(&flake.0)?.replace("${", "\\${");
+        let flake_nix = FLAKE_NIX.replace("\"@FLAKE_URL@\"", &flake_url);
+        std::fs::write(dir.path().join("flake.nix"), flake_nix)?;
+        std::fs::write(
+            dir.path().join("filters.json"),
+            serde_json::to_string(&filters)?,
+        )?;
+        Ok(FilteredFlake { dir })
+    }
+
+    /// URL of the wrapper flake
+    pub fn url(&self) -> FlakeUrl {
+        FlakeUrl(format!("path:{}", self.path().display()))
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Attribute paths (e.g. `packages.x86_64-linux.docs`) that are filtered out
+    ///
+    /// `override_inputs` are the `--override-input`s of the wrapped flake.
+    pub async fn filtered_attrs(
+        &self,
+        nixcmd: &NixCmd,
+        override_inputs: &BTreeMap<String, FlakeUrl>,
+    ) -> anyhow::Result<Vec<String>> {
+        let opts = FlakeOptions {
+            override_inputs: override_inputs
+                .iter()
+                .map(|(k, v)| (format!("flake/{}", k), v.clone()))
+                .collect(),
+            no_write_lock_file: true,
+            current_dir: None,
+        };
+        nix_eval(nixcmd, &opts, &self.url().with_attr("omnixFiltered"))
+            .await
+            .with_context(|| "Unable to evaluate the build step's include/exclude filters")
+    }
+}
+
+/// Convert an attribute path glob to a list of regexes (as understood by `builtins.match`), one per attribute name
+fn glob_to_regexes(glob: &str) -> Vec<String> {
+    glob.split('.')
+        .map(|name| {
+            let mut re = String::new();
+            for c in name.chars() {
+                match c {
+                    '*' => re.push_str(".*"),
+                    '?' => re.push('.'),
+                    '.' | '[' | ']' | '{' | '}' | '(' | ')' | '\\' | '+' | '^' | '$' | '|' => {
+                        re.push('\\');
+                        re.push(c);
+                    }
+                    c => re.push(c),
+                }
+            }
+            re
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_to_regexes() {
+        assert_eq!(
+            glob_to_regexes("packages.*.docs"),
+            vec!["packages", ".*", "docs"]
+        );
+        assert_eq!(
+            glob_to_regexes("checks.x86_64-linux.test-?+"),
+            vec!["checks", "x86_64-linux", "test-.\\+"]
+        );
+    }
+
+    #[test]
+    fn test_filtered_flake() {
+        let flake = FilteredFlake::new(
+            &FlakeUrl("github:juspay/omnix".to_string()),
+            &[],
+            &["nixosConfigurations.*".to_string()],
+        )
+        .unwrap();
+        let flake_nix = std::fs::read_to_string(flake.path().join("flake.nix")).unwrap();
+        assert!(flake_nix.contains(r#"inputs.flake.url = "github:juspay/omnix";"#));
+        let filters = std::fs::read_to_string(flake.path().join("filters.json")).unwrap();
+        assert_eq!(
+            filters,
+            r#"{"include":[],"exclude":[["nixosConfigurations",".*"]]}"#
+        );
+
+        let flake =
+            FilteredFlake::new(&FlakeUrl("path:./doc?lastModified=1".to_string()), &[], &[])
+                .unwrap();
+        let flake_nix = std::fs::read_to_string(flake.path().join("flake.nix")).unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        assert!(flake_nix.contains(&format!(
+            r#"inputs.flake.url = "path:{}/doc?lastModified=1";"#,
+            cwd.display()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_filter_flake_nix() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::write(
+            src.path().join("flake.nix"),
+            r#"{
+              outputs = _: {
+                packages.x86_64-linux = { default = 1; docs = 2; };
+                packages.aarch64-darwin = { default = 3; docs = 4; };
+                checks.x86_64-linux.test = 5;
+              };
+            }"#,
+        )
+        .unwrap();
+        let flake = FilteredFlake::new(
+            &FlakeUrl::from(src.path()),
+            &["packages".to_string()],
+            &["packages.*.docs".to_string()],
+        )
+        .unwrap();
+        let nixcmd = NixCmd::default();
+        let filtered = flake
+            .filtered_attrs(&nixcmd, &BTreeMap::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            filtered,
+            vec![
+                "checks",
+                "packages.aarch64-darwin.docs",
+                "packages.x86_64-linux.docs"
+            ]
+        );
+        let opts = FlakeOptions {
+            no_write_lock_file: true,
+            ..Default::default()
+        };
+        let outputs: serde_json::Value =
+            nix_eval(&nixcmd, &opts, &flake.url().with_attr("packages"))
+                .await
+                .unwrap();
+        assert_eq!(
+            outputs,
+            serde_json::json!({
+                "aarch64-darwin": { "default": 3 },
+                "x86_64-linux": { "default": 1 },
+            })
+        );
+    }
+}
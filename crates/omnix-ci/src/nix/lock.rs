@@ -5,15 +5,20 @@ use nix_rs::{
     command::NixCmd,
     flake::{self, command::FlakeOptions, url::FlakeUrl},
 };
+use tokio::process::Command;
 
 /// Make sure that the `flake.lock` file is in sync.
 pub async fn nix_flake_lock_check(nixcmd: &NixCmd, url: &FlakeUrl) -> Result<()> {
-    flake::command::lock(
+    nix_rs::command::run_command(&mut nix_flake_lock_check_cmd(nixcmd, url)).await?;
+    Ok(())
+}
+
+/// The [Command] run by [nix_flake_lock_check]
+pub fn nix_flake_lock_check_cmd(nixcmd: &NixCmd, url: &FlakeUrl) -> Command {
+    flake::command::lock_cmd(
         nixcmd,
         &FlakeOptions::default(),
         &["--no-update-lock-file"],
         url,
     )
-    .await?;
-    Ok(())
 }
@@ -1,3 +1,4 @@
 //! Nix-specific types and functions
 pub mod devour_flake;
+pub mod filter_flake;
 pub mod lock;
@@ -0,0 +1,84 @@
+//! Buildkite pipeline
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use serde::Serialize;
+
+use super::PipelineJob;
+
+/// A command step in a Buildkite pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildkiteStep {
+    /// Label shown in the Buildkite UI
+    pub label: String,
+    /// Shell command to run
+    pub command: String,
+    /// Agent tags that the agent running this step must have (e.g. `queue: nix`)
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub agents: BTreeMap<String, String>,
+}
+
+/// A Buildkite pipeline, to be uploaded using `buildkite-agent pipeline upload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildkitePipeline {
+    /// The steps
+    pub steps: Vec<BuildkiteStep>,
+}
+
+impl BuildkitePipeline {
+    /// Create a pipeline with the given jobs
+    ///
+    /// Their tags must be of the form `key=value`, as Buildkite targets agents by the value of their tags.
+    pub fn from(jobs: Vec<PipelineJob>) -> anyhow::Result<Self> {
+        let mut steps = vec![];
+        for job in jobs {
+            let mut agents = BTreeMap::new();
+            for tag in &job.tags {
+                let Some((key, value)) = tag.split_once('=') else {
+                    bail!(
+                        "Buildkite agent tags must be of the form key=value (e.g. queue={}), but got {:?}",
+                        tag,
+                        tag
+                    );
+                };
+                agents.insert(key.to_string(), value.to_string());
+            }
+            steps.push(BuildkiteStep {
+                label: job.name(),
+                command: job.command,
+                agents,
+            });
+        }
+        Ok(BuildkitePipeline { steps })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::tests::sample_jobs;
+
+    #[test]
+    fn test_buildkite_pipeline() {
+        let err = BuildkitePipeline::from(sample_jobs()).unwrap_err();
+        assert!(err.to_string().contains("key=value"), "{}", err);
+
+        let mut jobs = sample_jobs();
+        jobs[2].tags = vec!["queue=macos".to_string(), "nix=true".to_string()];
+        let yaml = serde_yaml::to_string(&BuildkitePipeline::from(jobs).unwrap()).unwrap();
+        assert_eq!(
+            yaml,
+            r#"steps:
+- label: ROOT (x86_64-linux)
+  command: om ci run --systems x86_64-linux '.#default.ROOT'
+- label: docs (x86_64-linux)
+  command: om ci run --systems x86_64-linux '.#default.docs'
+- label: ROOT (aarch64-darwin)
+  command: om ci run --systems aarch64-darwin '.#default.ROOT'
+  agents:
+    nix: 'true'
+    queue: macos
+"#
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! GitLab CI child pipeline
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::PipelineJob;
+
+/// A job in a GitLab CI pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GitLabJob {
+    /// Runner tags
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Shell commands to run
+    pub script: Vec<String>,
+}
+
+/// A GitLab CI pipeline, keyed by job name
+///
+/// Meant to be run as a [child pipeline](https://docs.gitlab.com/ee/ci/pipelines/downstream_pipelines.html#parent-child-pipelines).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GitLabPipeline(pub BTreeMap<String, GitLabJob>);
+
+impl GitLabPipeline {
+    /// Create a pipeline with the given jobs
+    ///
+    /// GitLab rejects pipelines without jobs, so a no-op job is added if there are none.
+    pub fn from(jobs: Vec<PipelineJob>) -> Self {
+        let mut pipeline: BTreeMap<String, GitLabJob> = jobs
+            .into_iter()
+            .map(|job| {
+                (
+                    job.name(),
+                    GitLabJob {
+                        tags: job.tags,
+                        script: vec![job.command],
+                    },
+                )
+            })
+            .collect();
+        if pipeline.is_empty() {
+            pipeline.insert(
+                "nothing to build".to_string(),
+                GitLabJob {
+                    tags: vec![],
+                    script: vec!["echo 'No subflakes to build'".to_string()],
+                },
+            );
+        }
+        GitLabPipeline(pipeline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::tests::sample_jobs;
+
+    #[test]
+    fn test_gitlab_pipeline() {
+        let yaml = serde_yaml::to_string(&GitLabPipeline::from(sample_jobs())).unwrap();
+        assert_eq!(
+            yaml,
+            r#"ROOT (aarch64-darwin):
+  tags:
+  - macos
+  - nix
+  script:
+  - om ci run --systems aarch64-darwin '.#default.ROOT'
+ROOT (x86_64-linux):
+  script:
+  - om ci run --systems x86_64-linux '.#default.ROOT'
+docs (x86_64-linux):
+  script:
+  - om ci run --systems x86_64-linux '.#default.docs'
+"#
+        );
+        assert!(GitLabPipeline::from(vec![])
+            .0
+            .contains_key("nothing to build"));
+    }
+}
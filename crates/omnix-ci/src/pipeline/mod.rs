@@ -0,0 +1,147 @@
+//! Pipeline generators for CI providers other than GitHub Actions
+//!
+//! Like [crate::github::matrix::GitHubMatrix], these turn the subflakes × systems
+//! matrix into one job per row, each running `om ci run` on a single subflake
+//! and system.
+pub mod buildkite;
+pub mod gitlab;
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use nix_rs::flake::system::System;
+
+use crate::{
+    changes::ChangedFiles, config::subflakes::SubflakesConfig, github::matrix::GitHubMatrix,
+};
+
+/// A job of the generated pipeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineJob {
+    /// System to build on
+    pub system: System,
+    /// Subflake to build
+    pub subflake: String,
+    /// The `om ci run` command line to run
+    pub command: String,
+    /// Tags selecting the runners (or agents) that can run this job
+    pub tags: Vec<String>,
+}
+
+impl PipelineJob {
+    /// Human-readable name of the job
+    pub fn name(&self) -> String {
+        format!("{} ({})", self.subflake, self.system)
+    }
+}
+
+/// Runner tags to use for the jobs of each system, given as `<SYSTEM>=<TAG>[,<TAG>...]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunnerTags {
+    /// The system the tags apply to
+    pub system: System,
+    /// The tags
+    pub tags: Vec<String>,
+}
+
+impl FromStr for RunnerTags {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (system, tags) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected <SYSTEM>=<TAG>[,<TAG>...], got '{}'", s))?;
+        Ok(RunnerTags {
+            system: System::from(system),
+            tags: tags
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// Create a job for each subflake and system
+///
+/// `config_name` is the CI configuration the subflakes belong to (i.e., `default` in `.#default.<subflake>`).
+/// If `changes` is given, only the subflakes affected by them are included.
+pub fn jobs(
+    config_name: &str,
+    systems: Vec<System>,
+    subflakes: &SubflakesConfig,
+    changes: Option<&ChangedFiles>,
+    runner_tags: &[RunnerTags],
+) -> Vec<PipelineJob> {
+    let tags_by_system: BTreeMap<&System, &Vec<String>> = runner_tags
+        .iter()
+        .map(|rt| (&rt.system, &rt.tags))
+        .collect();
+    GitHubMatrix::from(systems, subflakes, changes)
+        .include
+        .into_iter()
+        .map(|row| {
+            let flake = format!(".#{}.{}", config_name, row.subflake);
+            let command =
+                shell_words::join(["om", "ci", "run", "--systems", row.system.as_ref(), &flake]);
+            let tags = tags_by_system
+                .get(&row.system)
+                .map(|tags| tags.to_vec())
+                .unwrap_or_default();
+            PipelineJob {
+                system: row.system,
+                subflake: row.subflake,
+                command,
+                tags,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Jobs for two subflakes on two systems, with tags for one of them
+    pub(crate) fn sample_jobs() -> Vec<PipelineJob> {
+        let subflakes: SubflakesConfig = serde_json::from_value(serde_json::json!({
+            "ROOT": { "dir": "." },
+            "docs": { "dir": "doc", "systems": ["x86_64-linux"] },
+        }))
+        .unwrap();
+        let runner_tags = vec!["aarch64-darwin=macos,nix".parse().unwrap()];
+        jobs(
+            "default",
+            vec!["x86_64-linux".into(), "aarch64-darwin".into()],
+            &subflakes,
+            None,
+            &runner_tags,
+        )
+    }
+
+    #[test]
+    fn test_jobs() {
+        let jobs = sample_jobs();
+        let names: Vec<String> = jobs.iter().map(PipelineJob::name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "ROOT (x86_64-linux)",
+                "docs (x86_64-linux)",
+                "ROOT (aarch64-darwin)"
+            ]
+        );
+        assert_eq!(
+            jobs[0].command,
+            "om ci run --systems x86_64-linux '.#default.ROOT'"
+        );
+        assert!(jobs[0].tags.is_empty());
+        assert_eq!(jobs[2].tags, vec!["macos", "nix"]);
+    }
+
+    #[test]
+    fn test_parse_runner_tags() {
+        assert!("x86_64-linux".parse::<RunnerTags>().is_err());
+        let rt: RunnerTags = "x86_64-linux=".parse().unwrap();
+        assert!(rt.tags.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+//! Provenance of a CI run: where, when and with what it was run
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use nix_rs::{
+    command::NixCmd,
+    flake::command::{FlakeOptions, LockedFlake},
+    version::NixVersion,
+};
+use omnix_common::config::OmConfig;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::subflakes::effective_config;
+
+/// Metadata about a `om ci run` invocation, recorded in its results
+///
+/// Useful to track CI performance over time, and to reproduce a run from its result file.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RunMetadata {
+    /// Version of omnix that did the run
+    pub omnix_version: String,
+    /// Version of Nix used for the run
+    pub nix_version: NixVersion,
+    /// The flake, as locked by Nix
+    pub locked: LockedFlake,
+    /// Hostname of the machine the run happened on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// SHA-256 hash of the effective CI configuration (from `om.yaml` or the `om` flake output)
+    pub config_hash: String,
+    /// When the run started
+    pub started_at: DateTime<Utc>,
+    /// When the run finished
+    pub finished_at: DateTime<Utc>,
+}
+
+impl RunMetadata {
+    /// Gather the metadata of a run of the flake in `cfg`, which started at `started_at` and just finished.
+    pub async fn gather(
+        cmd: &NixCmd,
+        cfg: &OmConfig,
+        started_at: DateTime<Utc>,
+    ) -> anyhow::Result<Self> {
+        let nix_version = *NixVersion::get()
+            .await
+            .as_ref()
+            .with_context(|| "Unable to determine Nix version")?;
+        let locked =
+            nix_rs::flake::command::metadata(cmd, &FlakeOptions::default(), &cfg.flake_url)
+                .await
+                .with_context(|| format!("Unable to lock {}", cfg.flake_url))?;
+        Ok(RunMetadata {
+            omnix_version: env!("CARGO_PKG_VERSION").to_string(),
+            nix_version,
+            locked,
+            hostname: whoami::fallible::hostname().ok(),
+            config_hash: config_hash(cfg)?,
+            started_at,
+            finished_at: Utc::now(),
+        })
+    }
+}
+
+/// Hash of the CI configuration in effect, prefixed with the algorithm (`sha256:`)
+pub fn config_hash(cfg: &OmConfig) -> anyhow::Result<String> {
+    let (config, _) = cfg.get_sub_config_under::<serde_json::Value>("ci")?;
+    // Hash the configuration in effect, rather than how it is written (e.g., with `defaults`)
+    let config = effective_config(config).map_err(anyhow::Error::msg)?;
+    // Object keys are sorted by `serde_json`, so this serialization is canonical.
+    let json = serde_json::to_vec(&config)?;
+    Ok(format!("sha256:{:x}", Sha256::digest(json)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use nix_rs::flake::url::FlakeUrl;
+
+    use super::*;
+
+    fn om_config(config: serde_json::Value) -> OmConfig {
+        OmConfig {
+            flake_url: FlakeUrl::from_str(".").unwrap(),
+            reference: vec![],
+            config: serde_json::from_value(config).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_config_hash() {
+        let a = om_config(serde_json::json!({
+            "ci": { "default": { "foo": { "dir": "foo", "skip": true } } }
+        }));
+        let b = om_config(serde_json::json!({
+            "ci": { "default": { "foo": { "skip": true, "dir": "foo" } } },
+            "health": { "default": {} }
+        }));
+        let c = om_config(serde_json::json!({
+            "ci": { "default": { "foo": { "dir": "foo" } } }
+        }));
+        // Insensitive to key order and to non-CI configuration
+        assert_eq!(config_hash(&a).unwrap(), config_hash(&b).unwrap());
+        assert_ne!(config_hash(&a).unwrap(), config_hash(&c).unwrap());
+        assert!(config_hash(&a).unwrap().starts_with("sha256:"));
+        let d = om_config(serde_json::json!({
+            "ci": { "default": { "defaults": { "skip": true }, "foo": { "dir": "foo" } } }
+        }));
+        assert_eq!(config_hash(&a).unwrap(), config_hash(&d).unwrap());
+    }
+}
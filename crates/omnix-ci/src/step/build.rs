@@ -1,22 +1,30 @@
 //! The build step
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
 use clap::Parser;
 use colored::Colorize;
+use globset::{Glob, GlobSetBuilder};
 use nix_rs::{
     command::NixCmd,
+    events::{non_deterministic_derivation, NixEvent},
     flake::{functions::core::FlakeFn, url::FlakeUrl},
     store::{command::NixStoreCmd, path::StorePath},
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    command::run::RunCommand,
+    command::{plan::StepPlan, run::RunCommand},
     config::subflake::SubflakeConfig,
-    nix::devour_flake::{DevourFlake, DevourFlakeInput, DevourFlakeOutput},
+    nix::{
+        devour_flake::{DevourFlake, DevourFlakeInput, DevourFlakeOutput},
+        filter_flake::FilteredFlake,
+    },
 };
 
 /// Represents a build step in the CI pipeline
 ///
-/// It builds all flake outputs.
+/// It builds all flake outputs (or those selected by `include` and `exclude`).
 ///
 /// TODO: Should we use [`serde-bool`](https://docs.rs/serde-bool/latest/serde_bool/) to obviate that `Option` types in fields?
 #[derive(Debug, Clone, Deserialize)]
@@ -26,6 +34,22 @@ pub struct BuildStep {
     /// Whether to pass `--impure` to `nix build`
     #[serde(default)]
     pub impure: Option<bool>,
+    /// Only build the outputs matching these attribute path globs (e.g. `packages.*.default`)
+    ///
+    /// See [FilteredFlake] for the glob syntax.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Don't build the outputs matching these attribute path globs (e.g. `nixosConfigurations.*`)
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Whether to rebuild the outputs, to check that they are reproducible
+    ///
+    /// Also enabled by `om ci run --check-reproducible`.
+    #[serde(rename = "check-reproducible", default)]
+    pub check_reproducible: bool,
+    /// Names of the derivations (globs, such as `foo-*`) allowed to not be reproducible
+    #[serde(rename = "allow-non-reproducible", default)]
+    pub allow_non_reproducible: Vec<String>,
 }
 
 impl Default for BuildStep {
@@ -33,6 +57,10 @@ impl Default for BuildStep {
         BuildStep {
             enable: true,
             impure: None,
+            include: vec![],
+            exclude: vec![],
+            check_reproducible: false,
+            allow_non_reproducible: vec![],
         }
     }
 }
@@ -51,17 +79,37 @@ impl BuildStep {
             "{}",
             format!("⚒️  Building subflake: {}", subflake.dir).bold()
         );
-        let nix_args = subflake_extra_args(subflake);
+        let mut input = devour_flake_input(run_cmd, url, subflake);
+        let mut nix_args = subflake_extra_args(subflake, "");
+        let mut filtered_attrs = vec![];
+        // Kept alive until the build is done
+        let filter = self.filter(&input.flake)?;
+        if let Some(filter) = &filter {
+            filtered_attrs = filter
+                .filtered_attrs(nixcmd, &subflake.override_inputs)
+                .await?;
+            if !filtered_attrs.is_empty() {
+                tracing::info!(
+                    "{}",
+                    format!(
+                        "🚫 Not building {} filtered attribute(s): {}",
+                        filtered_attrs.len(),
+                        filtered_attrs.join(", ")
+                    )
+                    .dimmed()
+                );
+            }
+            input.flake = filter.url();
+            // The subflake's inputs are now those of the wrapped flake
+            nix_args = subflake_extra_args(subflake, "flake/");
+        }
         let output = DevourFlake::call(
             nixcmd,
             self.impure.unwrap_or(false),
             None,
             None,
             nix_args,
-            DevourFlakeInput {
-                flake: url.sub_flake_url(subflake.dir.clone()),
-                systems: run_cmd.systems.clone().map(|l| l.0),
-            },
+            input,
         )
         .await?
         .1;
@@ -69,6 +117,8 @@ impl BuildStep {
         let mut res = BuildStepResult {
             devour_flake_output: output,
             all_deps: None,
+            filtered_attrs,
+            non_reproducible: None,
         };
 
         if run_cmd.steps_args.build_step_args.include_all_dependencies {
@@ -81,16 +131,161 @@ impl BuildStep {
 
         Ok(res)
     }
+
+    /// Describe what [BuildStep::run] would do
+    pub fn plan(
+        &self,
+        nixcmd: &NixCmd,
+        run_cmd: &RunCommand,
+        url: &FlakeUrl,
+        subflake: &SubflakeConfig,
+    ) -> StepPlan {
+        if self.enable && (!self.include.is_empty() || !self.exclude.is_empty()) {
+            // The command builds a wrapper flake, which is only created when running
+            StepPlan::enabled("build")
+        } else if self.enable {
+            let cmd = DevourFlake::command(
+                nixcmd,
+                self.impure.unwrap_or(false),
+                None,
+                None,
+                &subflake_extra_args(subflake, ""),
+                &devour_flake_input(run_cmd, url, subflake),
+            );
+            StepPlan::run("build", &cmd)
+        } else {
+            StepPlan::skipped("build", "disabled")
+        }
+    }
+
+    /// Whether to check that the outputs are reproducible
+    pub fn should_check_reproducible(&self, run_cmd: &RunCommand) -> bool {
+        self.check_reproducible || run_cmd.steps_args.build_step_args.check_reproducible
+    }
+
+    /// Rebuild the derivations of `out_paths` (using `nix build --rebuild`), returning those whose outputs differ
+    ///
+    /// Fails if any derivation fails to rebuild for another reason.
+    pub async fn find_non_reproducible(
+        &self,
+        nixcmd: &NixCmd,
+        out_paths: &[StorePath],
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let mut drvs = NixStoreCmd.nix_store_query_deriver(out_paths).await?;
+        drvs.sort();
+        drvs.dedup();
+        tracing::info!(
+            "{}",
+            format!(
+                "🔁 Rebuilding {} derivation(s) to check reproducibility",
+                drvs.len()
+            )
+            .bold()
+        );
+        let mut non_reproducible = vec![];
+        let mut failed = vec![];
+        let res = nixcmd
+            .run_with_events(
+                &["build"],
+                |c| {
+                    c.args(["--rebuild", "--keep-going", "--no-link"]);
+                    c.args(drvs.iter().map(|drv| format!("{}^*", drv.display())));
+                },
+                |event| match event {
+                    NixEvent::BuildStarted { drv_path, .. } => {
+                        tracing::info!("   {}", drv_path.display().to_string().dimmed())
+                    }
+                    NixEvent::Message { message, .. } => {
+                        if let Some(drv) = non_deterministic_derivation(&message) {
+                            tracing::warn!(
+                                "{}",
+                                format!("🎲 Not reproducible: {}", drv.display()).yellow()
+                            );
+                            non_reproducible.push(drv);
+                        }
+                    }
+                    NixEvent::DerivationFailed { drv_path, .. } => failed.push(drv_path),
+                    _ => {}
+                },
+            )
+            .await;
+        match res {
+            Ok(_) => {}
+            // Nix fails when outputs differ, which is not an error here (unlike any other failure)
+            Err(_)
+                if !non_reproducible.is_empty()
+                    && failed.iter().all(|drv| non_reproducible.contains(drv)) => {}
+            Err(err) => return Err(err).context("Unable to rebuild the outputs"),
+        }
+        non_reproducible.sort();
+        non_reproducible.dedup();
+        Ok(non_reproducible)
+    }
+
+    /// Fail if any of the `non_reproducible` derivations is not allowed by `allow-non-reproducible`
+    pub fn ensure_reproducible(&self, non_reproducible: &[PathBuf]) -> anyhow::Result<()> {
+        let mut allowlist = GlobSetBuilder::new();
+        for pattern in &self.allow_non_reproducible {
+            allowlist.add(
+                Glob::new(pattern).with_context(|| {
+                    format!("Invalid glob in allow-non-reproducible: {}", pattern)
+                })?,
+            );
+        }
+        let allowlist = allowlist.build()?;
+        let disallowed: Vec<String> = non_reproducible
+            .iter()
+            .filter(|drv| !allowlist.is_match(derivation_name(drv)))
+            .map(|drv| drv.display().to_string())
+            .collect();
+        if !disallowed.is_empty() {
+            bail!(
+                "{} derivation(s) are not reproducible: {}",
+                disallowed.len(),
+                disallowed.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// The flake filtering `flake` by `include` and `exclude`, if either is set
+    fn filter(&self, flake: &FlakeUrl) -> anyhow::Result<Option<FilteredFlake>> {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return Ok(None);
+        }
+        FilteredFlake::new(flake, &self.include, &self.exclude).map(Some)
+    }
+}
+
+/// Name of a derivation, given its path (e.g. `hello-2.12` for `/nix/store/<hash>-hello-2.12.drv`)
+fn derivation_name(drv: &Path) -> &str {
+    let file_name = drv.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+    let name = file_name.strip_suffix(".drv").unwrap_or(file_name);
+    name.split_once('-').map_or(name, |(_hash, name)| name)
+}
+
+/// Input to devour-flake for building the given subflake
+fn devour_flake_input(
+    run_cmd: &RunCommand,
+    url: &FlakeUrl,
+    subflake: &SubflakeConfig,
+) -> DevourFlakeInput {
+    DevourFlakeInput {
+        flake: url.sub_flake_url(subflake.dir.clone()),
+        systems: run_cmd.systems.clone().map(|l| l.0),
+    }
 }
 
 /// Extra args to pass to devour-flake
-fn subflake_extra_args(subflake: &SubflakeConfig) -> Vec<String> {
+///
+/// `input_prefix` is prepended to the names of the overridden inputs.
+fn subflake_extra_args(subflake: &SubflakeConfig, input_prefix: &str) -> Vec<String> {
     let mut args = vec![];
 
     for (k, v) in &subflake.override_inputs {
         args.extend([
             "--override-input".to_string(),
-            k.to_string(),
+            format!("{}{}", input_prefix, k),
             v.0.to_string(),
         ])
     }
@@ -107,6 +302,12 @@ pub struct BuildStepArgs {
     /// useful to explicitly push all dependencies to a cache.
     #[clap(long, short = 'd')]
     pub include_all_dependencies: bool,
+
+    /// Rebuild the outputs, to check that they are reproducible
+    ///
+    /// See the `check-reproducible` option of the build step.
+    #[clap(long)]
+    pub check_reproducible: bool,
 }
 
 impl BuildStepArgs {
@@ -118,6 +319,10 @@ impl BuildStepArgs {
             args.push("--include-all-dependencies".to_owned());
         }
 
+        if self.check_reproducible {
+            args.push("--check-reproducible".to_owned());
+        }
+
         args
     }
 }
@@ -132,4 +337,47 @@ pub struct BuildStepResult {
     /// All dependencies of the out paths, if available
     #[serde(skip_serializing_if = "Option::is_none", rename = "allDeps")]
     pub all_deps: Option<Vec<StorePath>>,
+
+    /// Attribute paths not built, because of the `include` and `exclude` options
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        rename = "filteredAttrs"
+    )]
+    pub filtered_attrs: Vec<String>,
+
+    /// Derivations whose outputs differed when rebuilt, if checked (see [BuildStep::check_reproducible])
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "nonReproducible"
+    )]
+    pub non_reproducible: Option<Vec<PathBuf>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_reproducible() {
+        let build: BuildStep = serde_json::from_value(serde_json::json!({
+            "enable": true,
+            "check-reproducible": true,
+            "allow-non-reproducible": ["docs-*"],
+        }))
+        .unwrap();
+        assert!(build.check_reproducible);
+        let docs = PathBuf::from("/nix/store/ab12-docs-1.0.drv");
+        let hello = PathBuf::from("/nix/store/cd34-hello-2.12.drv");
+        assert_eq!(derivation_name(&hello), "hello-2.12");
+        assert!(build
+            .ensure_reproducible(std::slice::from_ref(&docs))
+            .is_ok());
+        let err = build.ensure_reproducible(&[docs, hello]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 derivation(s) are not reproducible: /nix/store/cd34-hello-2.12.drv"
+        );
+    }
 }
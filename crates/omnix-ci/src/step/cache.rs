@@ -0,0 +1,112 @@
+//! The cache step
+use std::path::PathBuf;
+
+use anyhow::Context;
+use colored::Colorize;
+use nix_rs::{
+    command::NixCmd,
+    copy::{nix_copy, NixCopyOptions},
+    sign::nix_store_sign,
+    store::{path::StorePath, uri::StoreURI},
+};
+use serde::Deserialize;
+
+use super::build::BuildStepResult;
+use crate::command::plan::StepPlan;
+
+/// Push the outputs of the build step to a binary cache, using `nix copy`
+///
+/// If `--include-all-dependencies` is passed, all build dependencies are pushed as well.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CacheStep {
+    /// Whether to enable this step
+    pub enable: bool,
+
+    /// The store to push to (e.g. `file:///var/cache/nix`, `s3://my-cache`, `ssh://cache-host`)
+    pub to: Option<StoreURI>,
+
+    /// Sign the paths with this secret key file before pushing
+    ///
+    /// The key can be generated using `nix key generate-secret`.
+    #[serde(rename = "secret-key", default)]
+    pub secret_key: Option<PathBuf>,
+}
+
+impl CacheStep {
+    /// Run this step, pushing the paths built in `build_res`
+    pub async fn run(&self, nixcmd: &NixCmd, build_res: &BuildStepResult) -> anyhow::Result<()> {
+        let to = self
+            .to
+            .as_ref()
+            .with_context(|| "The `cache` step requires a `to` store URI")?;
+        let paths: &[StorePath] = build_res
+            .all_deps
+            .as_deref()
+            .unwrap_or(&build_res.devour_flake_output.out_paths);
+        if paths.is_empty() {
+            tracing::info!("Nothing to push");
+            return Ok(());
+        }
+
+        if let Some(secret_key) = &self.secret_key {
+            tracing::info!("{}", format!("🔏 Signing {} paths", paths.len()).bold());
+            nix_store_sign(nixcmd, secret_key, paths.iter().map(StorePath::as_path))
+                .await
+                .with_context(|| format!("Unable to sign paths with {:?}", secret_key))?;
+        }
+
+        tracing::info!(
+            "{}",
+            format!("📤 Pushing {} paths to {}", paths.len(), to).bold()
+        );
+        nix_copy(
+            nixcmd,
+            NixCopyOptions {
+                to: Some(to.clone()),
+                ..Default::default()
+            },
+            paths.iter().map(StorePath::as_path),
+        )
+        .await
+        .with_context(|| format!("Unable to push to {}", to))?;
+        Ok(())
+    }
+
+    /// Describe what [CacheStep::run] would do
+    pub fn plan(&self) -> StepPlan {
+        if self.enable {
+            // The paths to push are only known after building.
+            StepPlan::enabled("cache")
+        } else {
+            StepPlan::skipped("cache", "disabled")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::step::core::Steps;
+
+    #[test]
+    fn test_cache_step_config() {
+        let steps: Steps = serde_json::from_value(serde_json::json!({
+            "cache": {
+                "enable": true,
+                "to": "file:///tmp/cache?compression=zstd",
+                "secret-key": "/run/secrets/cache.key"
+            }
+        }))
+        .unwrap();
+        let cache = steps.cache_step;
+        assert!(cache.enable);
+        assert_eq!(
+            cache.to.unwrap().to_string(),
+            "file:///tmp/cache?compression=zstd"
+        );
+        assert_eq!(
+            cache.secret_key.unwrap().to_str(),
+            Some("/run/secrets/cache.key")
+        );
+        assert!(!Steps::default().cache_step.enable);
+    }
+}
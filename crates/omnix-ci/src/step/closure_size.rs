@@ -0,0 +1,229 @@
+//! The closure-size step
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context};
+use bytesize::ByteSize;
+use colored::Colorize;
+use nix_rs::{
+    command::NixCmd,
+    flake::{command::FlakeOptions, eval::nix_eval, system::System, url::FlakeUrl},
+    path_info::nix_closure_sizes,
+    store::path::StorePath,
+};
+use serde::Deserialize;
+use tabled::{settings::Style, Table, Tabled};
+
+use super::build::BuildStepResult;
+use crate::{command::plan::StepPlan, config::subflake::SubflakeConfig};
+
+/// Check that the closure size of the given outputs of the build step are within budget
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClosureSizeStep {
+    /// Whether to enable this step
+    pub enable: bool,
+
+    /// Maximum closure size of each output, keyed by attribute path (e.g. `packages.x86_64-linux.default: 50MB`)
+    #[serde(default)]
+    pub budgets: BTreeMap<String, ByteSize>,
+}
+
+/// Closure size of an output, compared to its budget
+#[derive(Debug, Clone, PartialEq, Eq, Tabled)]
+struct BudgetRow {
+    #[tabled(rename = "output")]
+    attr: String,
+    #[tabled(rename = "closure size", display_with = "display_size")]
+    size: Option<ByteSize>,
+    budget: ByteSize,
+    status: String,
+}
+
+fn display_size(size: &Option<ByteSize>) -> String {
+    size.map_or_else(|| "-".to_string(), |s| s.to_string())
+}
+
+/// Flake outputs that are keyed by system (e.g. `packages.<system>.<name>`)
+const PER_SYSTEM_OUTPUTS: &[&str] = &[
+    "apps",
+    "checks",
+    "devShells",
+    "formatter",
+    "legacyPackages",
+    "packages",
+];
+
+impl ClosureSizeStep {
+    /// Run this step, checking the outputs built in `build_res`
+    ///
+    /// Outputs that were not built (e.g., for another system) are not checked.
+    pub async fn run(
+        &self,
+        nixcmd: &NixCmd,
+        systems: &[System],
+        url: &FlakeUrl,
+        subflake: &SubflakeConfig,
+        build_res: &BuildStepResult,
+    ) -> anyhow::Result<()> {
+        tracing::info!(
+            "{}",
+            format!(
+                "📏 Checking the closure size of {} output(s)",
+                self.budgets.len()
+            )
+            .bold()
+        );
+        let sub_flake_url = url.sub_flake_url(subflake.dir.clone());
+        let opts = FlakeOptions {
+            override_inputs: subflake.override_inputs.clone(),
+            ..Default::default()
+        };
+        let mut out_paths = BTreeMap::new();
+        for attr in self.attrs_for(systems) {
+            let out_path: StorePath = nix_eval(
+                nixcmd,
+                &opts,
+                &sub_flake_url.with_attr(&format!("{}.outPath", attr)),
+            )
+            .await
+            .with_context(|| format!("Unable to evaluate the output path of {}", attr))?;
+            if build_res.devour_flake_output.out_paths.contains(&out_path) {
+                out_paths.insert(attr, out_path);
+            }
+        }
+        let paths: Vec<StorePath> = out_paths.values().cloned().collect();
+        let sizes = nix_closure_sizes(nixcmd, None, &paths).await?;
+
+        let rows = self.check(
+            out_paths
+                .iter()
+                .map(|(attr, path)| (attr.as_str(), sizes.get(path).copied()))
+                .collect(),
+        );
+        let table = Table::new(&rows).with(Style::rounded()).to_string();
+        let exceeded: Vec<String> = rows
+            .iter()
+            .filter_map(|row| {
+                let size = row.size.filter(|size| *size > row.budget)?;
+                Some(format!("{} ({} > {})", row.attr, size, row.budget))
+            })
+            .collect();
+        if !exceeded.is_empty() {
+            bail!(
+                "{} output(s) exceed their closure size budget: {}\n{}",
+                exceeded.len(),
+                exceeded.join(", "),
+                table
+            );
+        }
+        tracing::info!("{}", table);
+        Ok(())
+    }
+
+    /// The attributes with a budget that may have been built for the given systems
+    ///
+    /// Outputs of other systems are left out without evaluating them, as they may not evaluate on this one (e.g., with import-from-derivation).
+    fn attrs_for(&self, systems: &[System]) -> Vec<&String> {
+        self.budgets
+            .keys()
+            .filter(|attr| {
+                let mut parts = attr.split('.');
+                match (parts.next(), parts.next()) {
+                    (Some(output), Some(system)) if PER_SYSTEM_OUTPUTS.contains(&output) => {
+                        systems.iter().any(|s| s.as_ref() == system)
+                    }
+                    _ => true,
+                }
+            })
+            .collect()
+    }
+
+    /// Compare the measured closure sizes (keyed by attribute path) to the budgets
+    fn check(&self, sizes: BTreeMap<&str, Option<ByteSize>>) -> Vec<BudgetRow> {
+        self.budgets
+            .iter()
+            .map(|(attr, &budget)| {
+                let size = sizes.get(attr.as_str()).copied();
+                let status = match size {
+                    None => "⏭️  not built",
+                    Some(None) => "❓ unknown",
+                    Some(Some(size)) if size > budget => "❌ over budget",
+                    Some(Some(_)) => "✅ ok",
+                };
+                BudgetRow {
+                    attr: attr.clone(),
+                    size: size.flatten(),
+                    budget,
+                    status: status.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// Describe what [ClosureSizeStep::run] would do
+    pub fn plan(&self) -> StepPlan {
+        if self.enable {
+            // The paths to measure are only known after building.
+            StepPlan::enabled("closure-size")
+        } else {
+            StepPlan::skipped("closure-size", "disabled")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::step::core::Steps;
+
+    use super::*;
+
+    #[test]
+    fn test_closure_size_budgets() {
+        let steps: Steps = serde_json::from_value(serde_json::json!({
+            "closure-size": {
+                "enable": true,
+                "budgets": {
+                    "packages.x86_64-linux.default": "50MB",
+                    "packages.x86_64-linux.docs": "1 GiB",
+                    "packages.aarch64-darwin.default": "50MB",
+                }
+            }
+        }))
+        .unwrap();
+        let step = steps.closure_size_step;
+        assert!(step.enable);
+        assert_eq!(
+            step.budgets["packages.x86_64-linux.default"],
+            ByteSize::mb(50)
+        );
+        let rows = step.check(BTreeMap::from([
+            ("packages.x86_64-linux.default", Some(ByteSize::mb(60))),
+            ("packages.x86_64-linux.docs", Some(ByteSize::mb(60))),
+        ]));
+        let statuses: Vec<&str> = rows.iter().map(|r| r.status.as_str()).collect();
+        assert_eq!(statuses, vec!["⏭️  not built", "❌ over budget", "✅ ok"]);
+    }
+
+    #[test]
+    fn test_closure_size_attrs_for() {
+        let step = ClosureSizeStep {
+            enable: true,
+            budgets: [
+                "packages.x86_64-linux.default",
+                "packages.aarch64-darwin.default",
+                "checks.aarch64-darwin.test",
+                "nixosConfigurations.server.config.system.build.toplevel",
+            ]
+            .into_iter()
+            .map(|attr| (attr.to_string(), ByteSize::mb(50)))
+            .collect(),
+        };
+        assert_eq!(
+            step.attrs_for(&[System::from("x86_64-linux")]),
+            vec![
+                "nixosConfigurations.server.config.system.build.toplevel",
+                "packages.x86_64-linux.default",
+            ]
+        );
+        assert_eq!(step.attrs_for(&[System::from("aarch64-darwin")]).len(), 3);
+    }
+}
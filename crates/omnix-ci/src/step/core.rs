@@ -1,5 +1,14 @@
 //! All CI steps available
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
 use clap::Parser;
+use colored::Colorize;
 use nix_rs::{
     command::NixCmd,
     flake::{system::System, url::FlakeUrl},
@@ -8,12 +17,41 @@ use serde::{Deserialize, Serialize};
 
 use super::{
     build::{BuildStep, BuildStepArgs, BuildStepResult},
-    custom::CustomSteps,
+    cache::CacheStep,
+    closure_size::ClosureSizeStep,
+    custom::{CustomSteps, Stage},
     flake_check::FlakeCheckStep,
+    fmt::FmtStep,
     lockfile::LockfileStep,
 };
-use crate::command::run::RunCommand;
+use crate::command::{plan::StepPlan, run::RunCommand};
 use crate::config::subflake::SubflakeConfig;
+use crate::events::{self, Event};
+
+/// Error of a step that stopped the run (i.e., without `--keep-going`)
+///
+/// Displays as the underlying error, but allows finding out which step failed
+/// (using [anyhow::Error::downcast_ref]).
+#[derive(Debug)]
+pub struct StepFailed {
+    /// Name of the step
+    pub step: String,
+    /// Why it failed
+    pub error: anyhow::Error,
+}
+
+impl Display for StepFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only the outermost message; the rest of the chain is reported via `source`.
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for StepFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
 
 /// CI steps to run
 ///
@@ -24,10 +62,18 @@ pub struct Steps {
     #[serde(default, rename = "lockfile")]
     pub lockfile_step: LockfileStep,
 
+    /// [FmtStep]
+    #[serde(default, rename = "fmt")]
+    pub fmt_step: FmtStep,
+
     /// [BuildStep]
     #[serde(default, rename = "build")]
     pub build_step: BuildStep,
 
+    /// [ClosureSizeStep]
+    #[serde(default, rename = "closure-size")]
+    pub closure_size_step: ClosureSizeStep,
+
     /// [FlakeCheckStep]
     #[serde(default, rename = "flake-check")]
     pub flake_check_step: FlakeCheckStep,
@@ -35,6 +81,10 @@ pub struct Steps {
     /// Custom steps
     #[serde(default, rename = "custom")]
     pub custom_steps: CustomSteps,
+
+    /// [CacheStep]
+    #[serde(default, rename = "cache")]
+    pub cache_step: CacheStep,
 }
 
 /// CLI arguments associated with [Steps]
@@ -51,10 +101,181 @@ pub struct StepsResult {
     /// [BuildStepResult]
     #[serde(rename = "build")]
     pub build_step: Option<BuildStepResult>,
+
+    /// Outcome of each step, in the order they were run
+    #[serde(default)]
+    pub steps: Vec<StepOutcome>,
+
+    /// The inputs overridden for the subflake (notably, those of its `overrideInputsMatrix` combination)
+    #[serde(
+        default,
+        rename = "overrideInputs",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub override_inputs: BTreeMap<String, FlakeUrl>,
+}
+
+/// Outcome of a single step
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StepOutcome {
+    /// Name of the step (custom steps are named `custom.<name>`)
+    pub name: String,
+    /// What happened when running the step
+    #[serde(flatten)]
+    pub status: StepStatus,
+    /// Wall-clock time taken by the step, in seconds
+    #[serde(default)]
+    pub duration: f64,
+    /// When the step started (absent for skipped steps)
+    #[serde(default, rename = "startedAt", skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the step finished (absent for skipped steps)
+    #[serde(
+        default,
+        rename = "finishedAt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// When a step was run, and for how long
+#[derive(Debug, Clone, Copy)]
+pub struct StepTiming {
+    /// When the step started
+    pub started_at: DateTime<Utc>,
+    /// Wall-clock time taken by the step
+    pub duration: Duration,
+}
+
+impl StepTiming {
+    /// Time the given future
+    pub async fn measure<F: Future>(fut: F) -> (F::Output, StepTiming) {
+        let started_at = Utc::now();
+        let start = Instant::now();
+        let output = fut.await;
+        let timing = StepTiming {
+            started_at,
+            duration: start.elapsed(),
+        };
+        (output, timing)
+    }
+
+    /// When the step finished
+    pub fn finished_at(&self) -> DateTime<Utc> {
+        // The duration is measured with a monotonic clock, so this cannot overflow in practice
+        self.started_at
+            + chrono::Duration::from_std(self.duration).unwrap_or_else(|_| chrono::Duration::zero())
+    }
+}
+
+/// Status of a step that `om ci run` went through
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum StepStatus {
+    /// The step ran successfully
+    Success,
+    /// The step failed (without `--keep-going`, this is the last step recorded)
+    Failure {
+        /// The error message
+        error: String,
+    },
+    /// The step was not run
+    Skipped {
+        /// Why the step was not run
+        reason: String,
+    },
+}
+
+impl StepsResult {
+    /// Run the step named `name` (by awaiting `fut`), recording its outcome.
+    ///
+    /// See [StepsResult::record] for the meaning of `keep_going`.
+    pub async fn run_step<T, Fut>(
+        &mut self,
+        name: impl Into<String>,
+        keep_going: bool,
+        fut: Fut,
+    ) -> anyhow::Result<Option<T>>
+    where
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let name = name.into();
+        events::emit(Event::StepStarted { step: name.clone() });
+        let (result, timing) = StepTiming::measure(fut).await;
+        self.record(name, result, timing, keep_going)
+    }
+
+    /// Record the result of running the step named `name`, which ran at `timing`.
+    ///
+    /// If the step failed, the failure is recorded; if `keep_going` is set,
+    /// `None` is then returned, otherwise the error is returned (as [StepFailed]).
+    pub fn record<T>(
+        &mut self,
+        name: impl Into<String>,
+        result: anyhow::Result<T>,
+        timing: StepTiming,
+        keep_going: bool,
+    ) -> anyhow::Result<Option<T>> {
+        let name = name.into();
+        match result {
+            Ok(v) => {
+                self.push(name, StepStatus::Success, Some(timing));
+                Ok(Some(v))
+            }
+            Err(err) => {
+                let status = StepStatus::Failure {
+                    error: format!("{:#}", err),
+                };
+                self.push(name.clone(), status, Some(timing));
+                if !keep_going {
+                    return Err(StepFailed {
+                        step: name,
+                        error: err,
+                    }
+                    .into());
+                }
+                tracing::error!("{}", format!("❌ Step {} failed: {:#}", name, err).red());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Record that the step named `name` was not run
+    pub fn record_skipped(&mut self, name: impl Into<String>, reason: impl Display) {
+        let status = StepStatus::Skipped {
+            reason: reason.to_string(),
+        };
+        self.push(name.into(), status, None);
+    }
+
+    fn push(&mut self, name: String, status: StepStatus, timing: Option<StepTiming>) {
+        let outcome = StepOutcome {
+            name,
+            status,
+            duration: timing.map_or(0.0, |t| t.duration.as_secs_f64()),
+            started_at: timing.map(|t| t.started_at),
+            finished_at: timing.map(|t| t.finished_at()),
+        };
+        events::emit(Event::StepFinished {
+            outcome: outcome.clone(),
+        });
+        self.steps.push(outcome);
+    }
+
+    /// The steps that failed
+    pub fn failures(&self) -> impl Iterator<Item = &StepOutcome> {
+        self.steps
+            .iter()
+            .filter(|step| matches!(step.status, StepStatus::Failure { .. }))
+    }
 }
 
 impl Steps {
     /// Run all CI steps
+    ///
+    /// Returns the outcome of the steps that were run, along with the error that stopped them, if any (i.e., unless `--keep-going`).
+    /// At most `jobs` custom steps are run at a time (see [CustomSteps::run]).
+    #[allow(clippy::too_many_arguments)]
     pub async fn run(
         &self,
         cmd: &NixCmd,
@@ -62,25 +283,176 @@ impl Steps {
         systems: &[System],
         url: &FlakeUrl,
         subflake: &SubflakeConfig,
-    ) -> anyhow::Result<StepsResult> {
-        let mut res = StepsResult::default();
+        jobs: usize,
+    ) -> (StepsResult, anyhow::Result<()>) {
+        let mut res = StepsResult {
+            override_inputs: subflake.override_inputs.clone(),
+            ..Default::default()
+        };
+        let outcome = self
+            .run_into(cmd, run_cmd, systems, url, subflake, jobs, &mut res)
+            .await;
+        (res, outcome)
+    }
+
+    /// Run all CI steps, recording their outcome in `res`
+    #[allow(clippy::too_many_arguments)]
+    async fn run_into(
+        &self,
+        cmd: &NixCmd,
+        run_cmd: &RunCommand,
+        systems: &[System],
+        url: &FlakeUrl,
+        subflake: &SubflakeConfig,
+        jobs: usize,
+        res: &mut StepsResult,
+    ) -> anyhow::Result<()> {
+        let keep_going = run_cmd.keep_going;
 
-        if self.lockfile_step.enable {
-            self.lockfile_step.run(cmd, url, subflake).await?;
+        match self.lockfile_step.skip_reason(subflake) {
+            Some(reason) => res.record_skipped("lockfile", reason),
+            None => {
+                let step = self.lockfile_step.run(cmd, url, subflake);
+                res.run_step("lockfile", keep_going, step).await?;
+            }
         }
 
+        if self.fmt_step.enable {
+            let step = self.fmt_step.run(cmd, url, subflake);
+            res.run_step("fmt", keep_going, step).await?;
+        } else {
+            res.record_skipped("fmt", "disabled");
+        }
+
+        self.custom_steps
+            .run(
+                cmd,
+                systems,
+                url,
+                subflake,
+                Stage::PreBuild,
+                keep_going,
+                jobs,
+                res,
+            )
+            .await?;
+
         if self.build_step.enable {
-            let build_res = self.build_step.run(cmd, run_cmd, url, subflake).await?;
-            res.build_step = Some(build_res);
+            let step = self.build_step.run(cmd, run_cmd, url, subflake);
+            res.build_step = res.run_step("build", keep_going, step).await?;
+            if let Some(build_res) = &res.build_step {
+                events::emit(Event::StorePaths {
+                    paths: build_res.devour_flake_output.out_paths.clone(),
+                });
+            }
+        } else {
+            res.record_skipped("build", "disabled");
+        }
+
+        if self.build_step.should_check_reproducible(run_cmd) {
+            let out_paths = res
+                .build_step
+                .as_ref()
+                .map(|b| b.devour_flake_output.out_paths.clone());
+            match out_paths {
+                Some(out_paths) => {
+                    events::emit(Event::StepStarted {
+                        step: "reproducible".to_string(),
+                    });
+                    let step = self.build_step.find_non_reproducible(cmd, &out_paths);
+                    let (found, timing) = StepTiming::measure(step).await;
+                    // Recorded even if the check fails, for the results JSON
+                    let result = found.and_then(|found| {
+                        let allowed = self.build_step.ensure_reproducible(&found);
+                        if let Some(build_res) = res.build_step.as_mut() {
+                            build_res.non_reproducible = Some(found);
+                        }
+                        allowed
+                    });
+                    res.record("reproducible", result, timing, keep_going)?;
+                }
+                None => res.record_skipped("reproducible", "nothing was built"),
+            }
+        }
+
+        match res.build_step.clone() {
+            Some(build_res) if self.closure_size_step.enable => {
+                let step = self
+                    .closure_size_step
+                    .run(cmd, systems, url, subflake, &build_res);
+                res.run_step("closure-size", keep_going, step).await?;
+            }
+            None if self.closure_size_step.enable => {
+                res.record_skipped("closure-size", "nothing was built")
+            }
+            _ => res.record_skipped("closure-size", "disabled"),
         }
 
         if self.flake_check_step.enable {
-            self.flake_check_step.run(cmd, url, subflake).await?;
+            let step = self.flake_check_step.run(cmd, url, subflake);
+            res.run_step("flake-check", keep_going, step).await?;
+        } else {
+            res.record_skipped("flake-check", "disabled");
+        }
+
+        self.custom_steps
+            .run(
+                cmd,
+                systems,
+                url,
+                subflake,
+                Stage::PostBuild,
+                keep_going,
+                jobs,
+                res,
+            )
+            .await?;
+
+        // Push last, so that (unless `--keep-going`) only fully checked outputs are pushed.
+        match res.build_step.clone() {
+            Some(build_res) if self.cache_step.enable => {
+                let step = self.cache_step.run(cmd, &build_res);
+                res.run_step("cache", keep_going, step).await?;
+            }
+            None if self.cache_step.enable => res.record_skipped("cache", "nothing was built"),
+            _ => res.record_skipped("cache", "disabled"),
         }
 
-        self.custom_steps.run(cmd, systems, url, subflake).await?;
+        Ok(())
+    }
+}
 
-        Ok(res)
+impl Steps {
+    /// Describe what [Steps::run] would do, in the order it would do it
+    pub fn plan(
+        &self,
+        cmd: &NixCmd,
+        run_cmd: &RunCommand,
+        systems: &[System],
+        url: &FlakeUrl,
+        subflake: &SubflakeConfig,
+    ) -> Vec<StepPlan> {
+        let mut res = vec![
+            self.lockfile_step.plan(cmd, url, subflake),
+            self.fmt_step.plan(),
+        ];
+        res.extend(
+            self.custom_steps
+                .plan(cmd, systems, subflake, Stage::PreBuild),
+        );
+        res.push(self.build_step.plan(cmd, run_cmd, url, subflake));
+        if self.build_step.should_check_reproducible(run_cmd) {
+            // The derivations to rebuild are only known after building.
+            res.push(StepPlan::enabled("reproducible"));
+        }
+        res.push(self.closure_size_step.plan());
+        res.push(self.flake_check_step.plan(cmd, url, subflake));
+        res.extend(
+            self.custom_steps
+                .plan(cmd, systems, subflake, Stage::PostBuild),
+        );
+        res.push(self.cache_step.plan());
+        res
     }
 }
 
@@ -90,3 +462,55 @@ impl StepsArgs {
         self.build_step_args.to_cli_args()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(secs: u64) -> StepTiming {
+        StepTiming {
+            started_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            duration: Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn test_record_keep_going() {
+        let mut res = StepsResult::default();
+        let ok = res.record("build", Ok(42), timing(0), true).unwrap();
+        assert_eq!(ok, Some(42));
+        let failed = res
+            .record::<()>("flake-check", Err(anyhow::anyhow!("boom")), timing(2), true)
+            .unwrap();
+        assert_eq!(failed, None);
+        res.record_skipped("custom.foo", "disabled");
+        assert_eq!(
+            res.failures().collect::<Vec<_>>(),
+            vec![&StepOutcome {
+                name: "flake-check".to_string(),
+                status: StepStatus::Failure {
+                    error: "boom".to_string()
+                },
+                duration: 2.0,
+                started_at: DateTime::from_timestamp(1_700_000_000, 0),
+                finished_at: DateTime::from_timestamp(1_700_000_002, 0),
+            }]
+        );
+        assert_eq!(res.steps.len(), 3);
+
+        // Without keep-going, the error is propagated, along with the name of the step
+        let err = res
+            .record::<()>(
+                "lockfile",
+                Err(anyhow::anyhow!("boom").context("Unable to lock")),
+                timing(0),
+                false,
+            )
+            .unwrap_err();
+        assert_eq!(format!("{:#}", err), "Unable to lock: boom");
+        assert_eq!(err.downcast_ref::<StepFailed>().unwrap().step, "lockfile");
+        // ... and the failure is recorded too
+        assert_eq!(res.failures().last().unwrap().name, "lockfile");
+        assert_eq!(res.steps.len(), 4);
+    }
+}
@@ -1,11 +1,18 @@
 //! Custom steps in the CI pipeline
 use colored::Colorize;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use nonempty::NonEmpty;
 use serde::Deserialize;
-use std::{collections::BTreeMap, future::Future, path::PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::process::Command;
 
 use nix_rs::{
-    command::NixCmd,
+    command::{log_prefix, with_log_prefix, NixCmd},
     flake::{
         self,
         system::System,
@@ -13,14 +20,75 @@ use nix_rs::{
     },
 };
 
-use crate::config::subflake::SubflakeConfig;
+use super::core::{StepStatus, StepTiming, StepsResult};
+use crate::{
+    command::plan::StepPlan,
+    config::subflake::SubflakeConfig,
+    events::{self, Event},
+};
 
 /// Represents a custom step in the CI pipeline
 ///
-/// All these commands are run in the same directory as the subflake
+/// All these commands are run in the same directory as the subflake (unless `cwd` is set)
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomStep {
+    /// What to run
+    #[serde(flatten)]
+    pub kind: CustomStepKind,
+
+    /// Whitelist of systems to run on
+    #[serde(default)]
+    pub systems: Option<Vec<System>>,
+
+    /// Environment variables to set
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Directory to run in, relative to the subflake directory
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+
+    /// Fail the step if it runs for longer than this (e.g. `"10m"`)
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+
+    /// Number of times to retry the step if it fails (or times out)
+    #[serde(default)]
+    pub retries: u32,
+
+    /// Names of the custom steps that must succeed before this one runs
+    #[serde(default)]
+    pub after: Vec<String>,
+
+    /// When to run, relative to the builtin steps
+    #[serde(default)]
+    pub stage: Stage,
+}
+
+/// When a custom step runs, relative to the builtin steps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    /// After the lockfile step, but before the build step
+    PreBuild,
+    /// After the build and flake-check steps
+    #[default]
+    PostBuild,
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::PreBuild => write!(f, "pre-build"),
+            Stage::PostBuild => write!(f, "post-build"),
+        }
+    }
+}
+
+/// The types of [CustomStep]
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type")]
-pub enum CustomStep {
+pub enum CustomStepKind {
     /// A flake app to run
     #[serde(rename = "app")]
     FlakeApp {
@@ -30,8 +98,6 @@ pub enum CustomStep {
         /// Arguments to pass to the app
         #[serde(default)]
         args: Vec<String>,
-        /// Whitelist of systems to run on
-        systems: Option<Vec<System>>,
     },
 
     /// An arbitrary command to run in the devshell
@@ -42,21 +108,30 @@ pub enum CustomStep {
         name: FlakeAttr,
         /// The command to run inside of devshell
         command: NonEmpty<String>,
-        /// Whitelist of systems to run on
-        systems: Option<Vec<System>>,
+    },
+
+    /// An arbitrary command to run directly, outside of any devshell
+    #[serde(rename = "command")]
+    Command {
+        /// The command to run
+        command: NonEmpty<String>,
     },
 }
 
 impl CustomStep {
     /// Run this step
+    ///
+    /// With `no_write_lock_file`, flake apps and devshells do not update `flake.lock` (as when steps run concurrently in the same directory).
     pub async fn run(
         &self,
         nixcmd: &NixCmd,
         url: &FlakeUrl,
         subflake: &SubflakeConfig,
+        no_write_lock_file: bool,
     ) -> anyhow::Result<()> {
         with_writeable_flake_dir(nixcmd, url, |flake_path| async move {
-            self.run_on_local_path(nixcmd, flake_path, subflake).await
+            self.run_on_local_path(nixcmd, flake_path, subflake, no_write_lock_file)
+                .await
         })
         .await
     }
@@ -67,86 +142,326 @@ impl CustomStep {
         nixcmd: &NixCmd,
         flake_path: PathBuf,
         subflake: &SubflakeConfig,
+        no_write_lock_file: bool,
     ) -> anyhow::Result<()> {
         let path = flake_path.join(&subflake.dir);
         tracing::info!("Running custom step under: {:}", &path.display());
+        let attempts = self.retries + 1;
+        for attempt in 1..=attempts {
+            match self
+                .run_once(self.command(nixcmd, path.clone(), subflake, no_write_lock_file))
+                .await
+            {
+                Err(err) if attempt < attempts => {
+                    tracing::warn!(
+                        "{}",
+                        format!(
+                            "🔁 Custom step failed ({:#}); retrying (attempt {}/{})",
+                            err,
+                            attempt + 1,
+                            attempts
+                        )
+                        .yellow()
+                    );
+                }
+                res => return res,
+            }
+        }
+        unreachable!("the last attempt always returns")
+    }
 
-        let pwd_flake = FlakeUrl::from(PathBuf::from("."));
+    /// Run the given [Command] once, honouring `timeout`
+    async fn run_once(&self, mut cmd: Command) -> anyhow::Result<()> {
+        // Don't leave the process running if it times out
+        cmd.kill_on_drop(true);
+        crate::events::keep_stdout_clear(&mut cmd);
+        let run = nix_rs::command::run_command(&mut cmd);
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out after {:?}", timeout))??,
+            None => run.await?,
+        };
+        Ok(())
+    }
+
+    /// The [Command] to run this step in the given (subflake) directory
+    fn command(
+        &self,
+        nixcmd: &NixCmd,
+        path: PathBuf,
+        subflake: &SubflakeConfig,
+        no_write_lock_file: bool,
+    ) -> Command {
+        // With `cwd`, the flake is no longer in the current directory
+        let (flake, cwd) = match &self.cwd {
+            Some(cwd) => {
+                let path = std::path::absolute(&path).unwrap_or(path);
+                (FlakeUrl::from(path.clone()), path.join(cwd))
+            }
+            None => (FlakeUrl::from(PathBuf::from(".")), path),
+        };
         let flake_opts = flake::command::FlakeOptions {
             override_inputs: subflake.override_inputs.clone(),
-            current_dir: Some(path.clone()),
-            no_write_lock_file: false,
+            current_dir: Some(cwd.clone()),
+            no_write_lock_file,
         };
 
-        match self {
-            CustomStep::FlakeApp { name, args, .. } => {
-                flake::command::run(
-                    nixcmd,
-                    &flake_opts,
-                    &pwd_flake.with_attr(&name.get_name()),
-                    args.clone(),
-                )
-                .await?;
-            }
-            CustomStep::FlakeDevShellCommand { name, command, .. } => {
-                flake::command::develop(
-                    nixcmd,
-                    &flake_opts,
-                    &pwd_flake.with_attr(&name.get_name()),
-                    command.clone(),
-                )
-                .await?;
+        let mut cmd = match &self.kind {
+            CustomStepKind::FlakeApp { name, args } => flake::command::run_cmd(
+                nixcmd,
+                &flake_opts,
+                &flake.with_attr(&name.get_name()),
+                args.clone(),
+            ),
+            CustomStepKind::FlakeDevShellCommand { name, command } => flake::command::develop_cmd(
+                nixcmd,
+                &flake_opts,
+                &flake.with_attr(&name.get_name()),
+                command.clone(),
+            ),
+            CustomStepKind::Command { command } => {
+                let mut cmd = Command::new(&command.head);
+                cmd.args(&command.tail);
+                cmd.current_dir(cwd);
+                cmd
             }
-        }
-        Ok(())
+        };
+        cmd.envs(&self.env);
+        cmd
     }
 
     fn can_run_on(&self, systems: &[System]) -> bool {
-        match self.get_systems() {
+        match &self.systems {
             Some(systems_whitelist) => systems_whitelist.iter().any(|s| systems.contains(s)),
             None => true,
         }
     }
-
-    fn get_systems(&self) -> &Option<Vec<System>> {
-        match self {
-            CustomStep::FlakeApp { systems, .. } => systems,
-            CustomStep::FlakeDevShellCommand { systems, .. } => systems,
-        }
-    }
 }
 
+/// Why a custom step is skipped when [CustomStep::can_run_on] is false
+const NOT_WHITELISTED: &str = "not whitelisted for the current systems";
+
 /// A collection of custom steps
+///
+/// Validated when loaded: steps can only be `after` existing steps of the same
+/// (or an earlier) [Stage], and their `after` dependencies cannot form a cycle.
 #[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "BTreeMap<String, CustomStep>")]
 pub struct CustomSteps(BTreeMap<String, CustomStep>);
 
+impl TryFrom<BTreeMap<String, CustomStep>> for CustomSteps {
+    type Error = String;
+
+    fn try_from(steps: BTreeMap<String, CustomStep>) -> Result<Self, Self::Error> {
+        for (name, step) in &steps {
+            for dep in &step.after {
+                let dep_step = steps.get(dep).ok_or_else(|| {
+                    format!("custom step '{}' is after unknown step '{}'", name, dep)
+                })?;
+                if step.stage < dep_step.stage {
+                    return Err(format!(
+                        "custom step '{}' ({}) cannot be after '{}' ({})",
+                        name, step.stage, dep, dep_step.stage
+                    ));
+                }
+            }
+        }
+        if let Some(cycle) = find_cycle(&steps) {
+            return Err(format!(
+                "custom steps cannot be after each other in a cycle: {}",
+                cycle.join(" → ")
+            ));
+        }
+        Ok(CustomSteps(steps))
+    }
+}
+
+/// Find a cycle in the `after` dependencies of `steps`, returning the names of the steps along it
+fn find_cycle(steps: &BTreeMap<String, CustomStep>) -> Option<Vec<&str>> {
+    /// Depth-first search, where `path` is the chain of steps being visited
+    fn visit<'a>(
+        name: &'a str,
+        steps: &'a BTreeMap<String, CustomStep>,
+        visited: &mut BTreeSet<&'a str>,
+        path: &mut Vec<&'a str>,
+    ) -> Option<Vec<&'a str>> {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name);
+            return Some(cycle);
+        }
+        if !visited.insert(name) {
+            return None;
+        }
+        path.push(name);
+        for dep in steps.get(name).into_iter().flat_map(|s| &s.after) {
+            if let Some(cycle) = visit(dep, steps, visited, path) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        None
+    }
+
+    let mut visited = BTreeSet::new();
+    steps
+        .keys()
+        .find_map(|name| visit(name, steps, &mut visited, &mut vec![]))
+}
+
 impl CustomSteps {
-    /// Run all custom steps
+    /// The steps of the given stage, in an order satisfying their `after` dependencies
+    ///
+    /// Independent steps are ordered by name.
+    fn in_stage(&self, stage: Stage) -> Vec<(&String, &CustomStep)> {
+        let mut pending: Vec<_> = self.0.iter().filter(|(_, s)| s.stage == stage).collect();
+        let mut ordered: Vec<(&String, &CustomStep)> = Vec::with_capacity(pending.len());
+        while !pending.is_empty() {
+            let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|(_, step)| {
+                step.after.iter().all(|dep| {
+                    self.0.get(dep).is_none_or(|s| s.stage != stage)
+                        || ordered.iter().any(|(name, _)| *name == dep)
+                })
+            });
+            if ready.is_empty() {
+                // Not possible for validated steps; see `TryFrom` above.
+                unreachable!("custom steps have a dependency cycle");
+            }
+            ordered.extend(ready);
+            pending = rest;
+        }
+        ordered
+    }
+
+    /// Run the custom steps of the given stage, recording their outcome in `res`
+    ///
+    /// Steps run in the order of [CustomSteps::in_stage], one at a time unless
+    /// `jobs` is greater than 1. In that case, steps run as soon as the steps
+    /// they are `after` have succeeded, at most `jobs` at a time (with their log
+    /// output prefixed by their name, and without updating `flake.lock`).
+    /// Steps after a failed step are skipped. See [StepsResult::record] for the meaning of `keep_going`.
+    #[allow(clippy::too_many_arguments)]
     pub async fn run(
         &self,
         nixcmd: &NixCmd,
         systems: &[System],
         url: &FlakeUrl,
         subflake: &SubflakeConfig,
+        stage: Stage,
+        keep_going: bool,
+        jobs: usize,
+        res: &mut StepsResult,
     ) -> anyhow::Result<()> {
-        for (name, step) in &self.0 {
-            if step.can_run_on(systems) {
-                tracing::info!("{}", format!("🏗  Running custom step: {}", name).bold());
-                step.run(nixcmd, url, subflake).await?;
-            } else {
-                tracing::info!(
-                  "{}",
-                  format!(
-                      "🏗  Skipping custom step {} because it's not whitelisted for the current system: {:?}",
-                      name,
-                      systems.iter().map(|s| s.to_string()).collect::<Vec<_>>()
-                  )
-                  .yellow()
-              );
+        let mut pending = self.in_stage(stage);
+        let concurrent = jobs > 1 && pending.len() > 1;
+        let mut running = FuturesUnordered::new();
+        while !pending.is_empty() || !running.is_empty() {
+            let mut waiting = vec![];
+            for (name, step) in pending {
+                if running.len() >= jobs {
+                    waiting.push((name, step));
+                    continue;
+                }
+                let step_name = format!("custom.{}", name);
+                let deps: Option<Vec<bool>> = step
+                    .after
+                    .iter()
+                    .map(|dep| dependency_succeeded(res, dep))
+                    .collect();
+                match deps {
+                    None => waiting.push((name, step)),
+                    Some(deps) if deps.iter().all(|ok| *ok) => {
+                        if step.can_run_on(systems) {
+                            tracing::info!(
+                                "{}",
+                                format!("🏗  Running custom step: {}", name).bold()
+                            );
+                            events::emit(Event::StepStarted {
+                                step: step_name.clone(),
+                            });
+                            let prefix = concurrent.then(|| match log_prefix() {
+                                Some(prefix) => format!("{} [{}]", prefix, name),
+                                None => format!("[{}]", name),
+                            });
+                            running.push(async move {
+                                let run = StepTiming::measure(
+                                    step.run(nixcmd, url, subflake, concurrent),
+                                );
+                                let outcome = match prefix {
+                                    Some(prefix) => with_log_prefix(prefix, run).await,
+                                    None => run.await,
+                                };
+                                (step_name, outcome)
+                            });
+                        } else {
+                            res.record_skipped(step_name, NOT_WHITELISTED);
+                            tracing::info!(
+                              "{}",
+                              format!(
+                                  "🏗  Skipping custom step {} because it's not whitelisted for the current system: {:?}",
+                                  name,
+                                  systems.iter().map(|s| s.to_string()).collect::<Vec<_>>()
+                              )
+                              .yellow()
+                          );
+                        }
+                    }
+                    Some(_) => {
+                        tracing::info!(
+                            "{}",
+                            format!("🏗  Skipping custom step {}: {}", name, AFTER_FAILED).yellow()
+                        );
+                        res.record_skipped(step_name, AFTER_FAILED);
+                    }
+                }
+            }
+            pending = waiting;
+
+            if let Some((step_name, (result, timing))) = running.next().await {
+                res.record(step_name, result, timing, keep_going)?;
             }
         }
         Ok(())
     }
+
+    /// Describe what [CustomSteps::run] would do for the given stage
+    pub fn plan(
+        &self,
+        nixcmd: &NixCmd,
+        systems: &[System],
+        subflake: &SubflakeConfig,
+        stage: Stage,
+    ) -> Vec<StepPlan> {
+        self.in_stage(stage)
+            .into_iter()
+            .map(|(name, step)| {
+                let name = format!("custom.{}", name);
+                if step.can_run_on(systems) {
+                    let path = PathBuf::from(&subflake.dir);
+                    StepPlan::run(name, &step.command(nixcmd, path, subflake, false))
+                } else {
+                    StepPlan::skipped(name, NOT_WHITELISTED)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Why a custom step is skipped when a step it is `after` did not succeed
+const AFTER_FAILED: &str = "a step it is after did not succeed";
+
+/// Whether the custom step named `dep` succeeded, or `None` if it has not finished yet
+///
+/// Steps skipped because they are not whitelisted for the current systems count as succeeded.
+fn dependency_succeeded(res: &StepsResult, dep: &str) -> Option<bool> {
+    let name = format!("custom.{}", dep);
+    let outcome = res.steps.iter().find(|s| s.name == name)?;
+    Some(match &outcome.status {
+        StepStatus::Success => true,
+        StepStatus::Skipped { reason } => reason == NOT_WHITELISTED,
+        StepStatus::Failure { .. } => false,
+    })
 }
 
 /// Call the given function with a (write-able) local path equivalent to the given URL
@@ -191,3 +506,153 @@ where
     // Finally, call the function with the path
     f(path).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_step_config() {
+        let steps: CustomSteps = serde_json::from_value(serde_json::json!({
+            "integration": {
+                "type": "command",
+                "command": ["cargo", "test", "--", "--ignored"],
+                "env": { "RUST_LOG": "debug" },
+                "cwd": "tests",
+                "timeout": "10m",
+                "retries": 2,
+            },
+            "fmt": {
+                "type": "devshell",
+                "command": ["treefmt"],
+                "systems": ["x86_64-linux"],
+            },
+        }))
+        .unwrap();
+
+        let integration = &steps.0["integration"];
+        assert_eq!(integration.timeout, Some(Duration::from_secs(600)));
+        assert_eq!(integration.retries, 2);
+        let cmd = integration.command(
+            &NixCmd::default(),
+            PathBuf::from("/src"),
+            &SubflakeConfig::default(),
+            false,
+        );
+        let cmd = cmd.as_std();
+        assert_eq!(cmd.get_program(), "cargo");
+        assert_eq!(
+            cmd.get_current_dir(),
+            Some(PathBuf::from("/src/tests").as_path())
+        );
+        assert_eq!(
+            cmd.get_envs().collect::<Vec<_>>(),
+            vec![("RUST_LOG".as_ref(), Some("debug".as_ref()))]
+        );
+
+        let fmt = &steps.0["fmt"];
+        assert!(matches!(
+            fmt.kind,
+            CustomStepKind::FlakeDevShellCommand { .. }
+        ));
+        assert_eq!((fmt.timeout, fmt.retries), (None, 0));
+        assert!(!fmt.can_run_on(&["aarch64-darwin".into()]));
+    }
+
+    fn custom_steps(json: serde_json::Value) -> Result<CustomSteps, String> {
+        serde_json::from_value(json).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_custom_steps_validation() {
+        let err = custom_steps(serde_json::json!({
+            "a": { "type": "command", "command": ["true"], "after": ["c"] },
+            "b": { "type": "command", "command": ["true"], "after": ["a"] },
+            "c": { "type": "command", "command": ["true"], "after": ["b"] },
+        }))
+        .unwrap_err();
+        assert!(err.contains("cycle: a → c → b → a"), "{}", err);
+
+        let err = custom_steps(serde_json::json!({
+            "a": { "type": "command", "command": ["true"], "after": ["nope"] },
+        }))
+        .unwrap_err();
+        assert!(err.contains("after unknown step 'nope'"), "{}", err);
+
+        let err = custom_steps(serde_json::json!({
+            "a": { "type": "command", "command": ["true"], "stage": "pre-build", "after": ["b"] },
+            "b": { "type": "command", "command": ["true"] },
+        }))
+        .unwrap_err();
+        assert!(
+            err.contains("'a' (pre-build) cannot be after 'b' (post-build)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_in_stage() {
+        let steps = custom_steps(serde_json::json!({
+            "a": { "type": "command", "command": ["true"], "after": ["c", "setup"] },
+            "b": { "type": "command", "command": ["true"] },
+            "c": { "type": "command", "command": ["true"] },
+            "setup": { "type": "command", "command": ["true"], "stage": "pre-build" },
+        }))
+        .unwrap();
+        let names = |stage| {
+            steps
+                .in_stage(stage)
+                .into_iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(Stage::PreBuild), vec!["setup"]);
+        assert_eq!(names(Stage::PostBuild), vec!["b", "c", "a"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_after_failed() {
+        let steps = custom_steps(serde_json::json!({
+            "fails": { "type": "command", "command": ["false"] },
+            "after-fails": { "type": "command", "command": ["true"], "after": ["fails"] },
+            "independent": { "type": "command", "command": ["true"] },
+        }))
+        .unwrap();
+        // Sequentially, and concurrently
+        for jobs in [1, 2] {
+            let dir = tempfile::tempdir().unwrap();
+            let subflake = SubflakeConfig::default();
+            let mut res = StepsResult::default();
+            steps
+                .run(
+                    &NixCmd::default(),
+                    &[],
+                    &FlakeUrl::from(dir.path()),
+                    &subflake,
+                    Stage::PostBuild,
+                    true,
+                    jobs,
+                    &mut res,
+                )
+                .await
+                .unwrap();
+            let statuses: BTreeMap<&str, &StepStatus> = res
+                .steps
+                .iter()
+                .map(|s| (s.name.as_str(), &s.status))
+                .collect();
+            assert!(matches!(
+                statuses["custom.fails"],
+                StepStatus::Failure { .. }
+            ));
+            assert_eq!(
+                statuses["custom.after-fails"],
+                &StepStatus::Skipped {
+                    reason: AFTER_FAILED.to_string()
+                }
+            );
+            assert_eq!(statuses["custom.independent"], &StepStatus::Success);
+        }
+    }
+}
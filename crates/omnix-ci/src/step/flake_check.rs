@@ -6,7 +6,7 @@ use nix_rs::{
 };
 use serde::Deserialize;
 
-use crate::config::subflake::SubflakeConfig;
+use crate::{command::plan::StepPlan, config::subflake::SubflakeConfig};
 
 /// Run `nix flake check`
 ///
@@ -32,11 +32,27 @@ impl FlakeCheckStep {
             format!("🩺 Running flake check on: {}", subflake.dir).bold()
         );
         let sub_flake_url = url.sub_flake_url(subflake.dir.clone());
-        let opts = FlakeOptions {
-            override_inputs: subflake.override_inputs.clone(),
-            ..Default::default()
-        };
-        flake::command::check(nixcmd, &opts, &sub_flake_url).await?;
+        flake::command::check(nixcmd, &flake_opts(subflake), &sub_flake_url).await?;
         Ok(())
     }
+
+    /// Describe what [FlakeCheckStep::run] would do
+    pub fn plan(&self, nixcmd: &NixCmd, url: &FlakeUrl, subflake: &SubflakeConfig) -> StepPlan {
+        if self.enable {
+            let sub_flake_url = url.sub_flake_url(subflake.dir.clone());
+            StepPlan::run(
+                "flake-check",
+                &flake::command::check_cmd(nixcmd, &flake_opts(subflake), &sub_flake_url),
+            )
+        } else {
+            StepPlan::skipped("flake-check", "disabled")
+        }
+    }
+}
+
+fn flake_opts(subflake: &SubflakeConfig) -> FlakeOptions {
+    FlakeOptions {
+        override_inputs: subflake.override_inputs.clone(),
+        ..Default::default()
+    }
 }
@@ -0,0 +1,187 @@
+//! The fmt step
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use colored::Colorize;
+use nix_rs::{
+    command::NixCmd,
+    config::NixConfig,
+    flake::{self, command::FlakeOptions, url::FlakeUrl},
+};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{command::plan::StepPlan, config::subflake::SubflakeConfig};
+
+/// Check that the subflake is formatted, using its `formatter` (as `nix fmt` would)
+///
+/// The formatter is run over a scratch copy of the subflake, so the source is never modified.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FmtStep {
+    /// Whether to enable this step
+    pub enable: bool,
+}
+
+impl FmtStep {
+    /// Run this step
+    pub async fn run(
+        &self,
+        nixcmd: &NixCmd,
+        url: &FlakeUrl,
+        subflake: &SubflakeConfig,
+    ) -> anyhow::Result<()> {
+        tracing::info!(
+            "{}",
+            format!("🎨 Checking formatting of {}", subflake.dir).bold()
+        );
+        let system = &NixConfig::get().await.as_ref()?.system.value;
+        // Only the files known to Nix (e.g., tracked by git) are checked.
+        let locked = flake::command::metadata(nixcmd, &FlakeOptions::default(), url).await?;
+        let source = locked
+            .path
+            .with_context(|| format!("Unable to find the source of {}", url))?
+            .join(&subflake.dir);
+        let scratch = tempfile::Builder::new().prefix("om-ci-fmt-").tempdir()?;
+        omnix_common::fs::copy_dir_all(&source, scratch.path())
+            .await
+            .with_context(|| format!("Unable to copy {:?}", source))?;
+
+        let mut cmd = fmt_cmd(nixcmd, url, subflake, system.as_ref());
+        cmd.current_dir(scratch.path());
+        crate::events::keep_stdout_clear(&mut cmd);
+        nix_rs::command::run_command(&mut cmd)
+            .await
+            .with_context(|| "Unable to run the formatter")?;
+
+        let changed = changed_files(&source, scratch.path()).await?;
+        if !changed.is_empty() {
+            print_diff(&source, scratch.path()).await;
+            bail!(
+                "{} file(s) are not formatted: {}",
+                changed.len(),
+                changed
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Describe what [FmtStep::run] would do
+    pub fn plan(&self) -> StepPlan {
+        if self.enable {
+            // The formatter is run in a scratch copy, which only exists when running.
+            StepPlan::enabled("fmt")
+        } else {
+            StepPlan::skipped("fmt", "disabled")
+        }
+    }
+}
+
+/// The `nix run` command running the `formatter` of the subflake for `system` (over the current directory)
+fn fmt_cmd(nixcmd: &NixCmd, url: &FlakeUrl, subflake: &SubflakeConfig, system: &str) -> Command {
+    // The formatter is run in another directory, so the flake must not be relative to the current one
+    let url = match url.as_local_path() {
+        Some(path) => FlakeUrl::from(std::path::absolute(path).unwrap_or(path.to_path_buf())),
+        None => url.clone(),
+    };
+    let flake_opts = FlakeOptions {
+        override_inputs: subflake.override_inputs.clone(),
+        ..Default::default()
+    };
+    flake::command::run_cmd(
+        nixcmd,
+        &flake_opts,
+        &url.sub_flake_url(subflake.dir.clone())
+            .with_attr(&format!("formatter.{}", system)),
+        vec![".".to_string()],
+    )
+}
+
+/// Files (relative to both directories) that differ between `old` and `new`, or are only in one of them
+async fn changed_files(old: &Path, new: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = omnix_common::fs::find_paths(old).await?;
+    paths.extend(omnix_common::fs::find_paths(new).await?);
+    paths.sort();
+    paths.dedup();
+    let mut changed = vec![];
+    for path in paths {
+        if read_entry(&old.join(&path))? != read_entry(&new.join(&path))? {
+            changed.push(path);
+        }
+    }
+    Ok(changed)
+}
+
+/// What is at `path`, for the purpose of comparing it: the target of a symlink, the contents of a file, or nothing
+fn read_entry(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return Ok(None);
+    };
+    let entry = if metadata.is_symlink() {
+        Some(
+            std::fs::read_link(path)?
+                .into_os_string()
+                .into_encoded_bytes(),
+        )
+    } else if metadata.is_file() {
+        Some(std::fs::read(path)?)
+    } else {
+        None
+    };
+    Ok(entry)
+}
+
+/// Print the changes made by the formatter, if `diff` is available
+async fn print_diff(old: &Path, new: &Path) {
+    let output = Command::new("diff")
+        .args(["-ru", "--no-dereference"])
+        .arg(old)
+        .arg(new)
+        .output()
+        .await;
+    match output {
+        Ok(output) => tracing::info!("{}", String::from_utf8_lossy(&output.stdout)),
+        Err(err) => tracing::debug!("Unable to run diff: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_changed_files() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        for dir in [&old, &new] {
+            std::fs::create_dir(dir.path().join("src")).unwrap();
+            std::fs::write(dir.path().join("src/same.rs"), "fn main() {}\n").unwrap();
+        }
+        std::fs::write(old.path().join("src/lib.rs"), "fn  f(){}").unwrap();
+        std::fs::write(new.path().join("src/lib.rs"), "fn f() {}\n").unwrap();
+        std::fs::write(new.path().join("new.txt"), "").unwrap();
+        assert_eq!(
+            changed_files(old.path(), new.path()).await.unwrap(),
+            vec![PathBuf::from("new.txt"), PathBuf::from("src/lib.rs")]
+        );
+    }
+
+    #[test]
+    fn test_fmt_cmd() {
+        let subflake = SubflakeConfig {
+            dir: "doc".to_string(),
+            ..Default::default()
+        };
+        let cmd = fmt_cmd(
+            &NixCmd::default(),
+            &FlakeUrl("github:juspay/omnix".to_string()),
+            &subflake,
+            "x86_64-linux",
+        );
+        assert!(nix_rs::command::to_cli(&cmd)
+            .ends_with("run 'github:juspay/omnix?dir=doc#formatter.x86_64-linux' -- ."));
+    }
+}
@@ -1,20 +1,50 @@
 //! The lockfile step
+use std::time::Duration;
+
+use anyhow::bail;
+use chrono::{DateTime, Utc};
 use colored::Colorize;
-use nix_rs::{command::NixCmd, flake::url::FlakeUrl};
+use humantime_serde::re::humantime;
+use nix_rs::{
+    command::NixCmd,
+    flake::{command::FlakeOptions, lock::FlakeLock, url::FlakeUrl},
+};
 use serde::Deserialize;
 
-use crate::{config::subflake::SubflakeConfig, nix};
+use crate::{command::plan::StepPlan, config::subflake::SubflakeConfig, nix};
 
-/// Check that `flake.lock` is not out of date.
+/// Check that `flake.lock` is not out of date, and that it follows the configured policies.
 #[derive(Debug, Clone, Deserialize)]
 pub struct LockfileStep {
     /// Whether to enable this step
     pub enable: bool,
+
+    /// Maximum age of the inputs in `max-age-inputs`, based on their `lastModified` (e.g. `30d`)
+    #[serde(rename = "max-age", default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+
+    /// Inputs of the flake whose age is checked against `max-age` (default: all of them)
+    #[serde(rename = "max-age-inputs", default)]
+    pub max_age_inputs: Vec<String>,
+
+    /// Inputs (e.g. `nixpkgs`) that must be locked only once, i.e. that the inputs of the flake's inputs must `follow`
+    #[serde(rename = "forbid-duplicates", default)]
+    pub forbid_duplicates: Vec<String>,
+
+    /// Whether to forbid unlocked inputs, as well as `path:` inputs
+    #[serde(rename = "forbid-unlocked", default)]
+    pub forbid_unlocked: bool,
 }
 
 impl Default for LockfileStep {
     fn default() -> Self {
-        LockfileStep { enable: true }
+        LockfileStep {
+            enable: true,
+            max_age: None,
+            max_age_inputs: vec![],
+            forbid_duplicates: vec![],
+            forbid_unlocked: false,
+        }
     }
 }
 
@@ -26,14 +56,237 @@ impl LockfileStep {
         url: &FlakeUrl,
         subflake: &SubflakeConfig,
     ) -> anyhow::Result<()> {
-        if subflake.override_inputs.is_empty() {
+        tracing::info!(
+            "{}",
+            format!("🫀 Checking that {}/flake.lock is up-to-date", subflake.dir).bold()
+        );
+        let sub_flake_url = url.sub_flake_url(subflake.dir.clone());
+        nix::lock::nix_flake_lock_check(nixcmd, &sub_flake_url).await?;
+
+        if self.has_policies() {
             tracing::info!(
                 "{}",
-                format!("🫀 Checking that {}/flake.lock is up-to-date", subflake.dir).bold()
+                format!("📜 Checking {}/flake.lock policies", subflake.dir).bold()
             );
-            let sub_flake_url = url.sub_flake_url(subflake.dir.clone());
-            nix::lock::nix_flake_lock_check(nixcmd, &sub_flake_url).await?;
+            let lock =
+                FlakeLock::from_nix(nixcmd, &FlakeOptions::default(), &sub_flake_url).await?;
+            let violations = self.violations(&lock, Utc::now());
+            if !violations.is_empty() {
+                bail!(
+                    "{}/flake.lock violates the lockfile policies:\n{}",
+                    subflake.dir,
+                    violations
+                        .iter()
+                        .map(|v| format!("  - {}", v))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+            }
         }
         Ok(())
     }
+
+    /// Whether any policy on the contents of `flake.lock` is configured
+    fn has_policies(&self) -> bool {
+        self.max_age.is_some() || !self.forbid_duplicates.is_empty() || self.forbid_unlocked
+    }
+
+    /// The policies that `lock` violates, as of `now`, one message per violation
+    pub fn violations(&self, lock: &FlakeLock, now: DateTime<Utc>) -> Vec<String> {
+        let mut res = vec![];
+        let input_paths = lock.input_paths();
+        let display_path = |key: &str| {
+            input_paths
+                .get(key)
+                .map_or_else(|| key.to_string(), |path| path.join("/"))
+        };
+
+        if let Some(max_age) = self.max_age {
+            let inputs: Vec<String> = if self.max_age_inputs.is_empty() {
+                lock.root_node()
+                    .map(|root| root.inputs.keys().cloned().collect())
+                    .unwrap_or_default()
+            } else {
+                self.max_age_inputs.clone()
+            };
+            for input in inputs {
+                let Some(key) = lock.resolve(std::slice::from_ref(&input)) else {
+                    res.push(format!(
+                        "Input '{}' (in max-age-inputs) is not an input of the flake",
+                        input
+                    ));
+                    continue;
+                };
+                let last_modified = lock.nodes[key]
+                    .locked
+                    .as_ref()
+                    .and_then(|locked| locked.last_modified)
+                    .and_then(|t| DateTime::from_timestamp(t, 0));
+                let Some(last_modified) = last_modified else {
+                    res.push(format!(
+                        "Input '{}' has no lastModified, so its age cannot be checked",
+                        input
+                    ));
+                    continue;
+                };
+                let age = (now - last_modified).to_std().unwrap_or_default();
+                if age > max_age {
+                    res.push(format!(
+                        "Input '{}' was last modified {} days ago ({}), which is more than the max-age of {}; run `nix flake update {}`",
+                        input,
+                        age.as_secs() / 86400,
+                        last_modified.format("%Y-%m-%d"),
+                        humantime::format_duration(max_age),
+                        input
+                    ));
+                }
+            }
+        }
+
+        for name in &self.forbid_duplicates {
+            let instances: Vec<&Vec<String>> = input_paths
+                .iter()
+                .filter(|(key, _)| is_instance_of(key, name))
+                .map(|(_, path)| path)
+                .collect();
+            if instances.len() > 1 {
+                let paths: Vec<String> = instances.iter().map(|p| p.join("/")).collect();
+                let example = instances
+                    .iter()
+                    .find(|p| p.len() > 1)
+                    .map(|p| {
+                        format!(
+                            " (e.g. `inputs.{}.follows = \"{}\";`)",
+                            p.join(".inputs."),
+                            name
+                        )
+                    })
+                    .unwrap_or_default();
+                res.push(format!(
+                    "Input '{}' is locked {} times, as: {}; make them `follow` a single instance{}",
+                    name,
+                    instances.len(),
+                    paths.join(", "),
+                    example
+                ));
+            }
+        }
+
+        if self.forbid_unlocked {
+            for key in input_paths.keys() {
+                match &lock.nodes[*key].locked {
+                    None => res.push(format!("Input '{}' is not locked", display_path(key))),
+                    Some(locked) if locked.type_ == "path" => res.push(format!(
+                        "Input '{}' is a `path:` input ({}), which is not reproducible elsewhere",
+                        display_path(key),
+                        locked
+                            .attrs
+                            .get("path")
+                            .and_then(|p| p.as_str())
+                            .unwrap_or("?")
+                    )),
+                    Some(locked) if locked.rev.is_none() && locked.nar_hash.is_none() => {
+                        res.push(format!(
+                            "Input '{}' is not locked to a revision or hash",
+                            display_path(key)
+                        ))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        res
+    }
+
+    /// Why this step should not be run for the given subflake, if it shouldn't
+    pub fn skip_reason(&self, subflake: &SubflakeConfig) -> Option<&'static str> {
+        if !self.enable {
+            Some("disabled")
+        } else if !subflake.override_inputs.is_empty() {
+            // The lock file cannot be checked when inputs are overriden
+            Some("subflake overrides inputs")
+        } else {
+            None
+        }
+    }
+
+    /// Describe what [LockfileStep::run] would do
+    ///
+    /// The policies are not part of the plan, as they are checked by parsing `flake.lock`.
+    pub fn plan(&self, nixcmd: &NixCmd, url: &FlakeUrl, subflake: &SubflakeConfig) -> StepPlan {
+        match self.skip_reason(subflake) {
+            Some(reason) => StepPlan::skipped("lockfile", reason),
+            None => {
+                let sub_flake_url = url.sub_flake_url(subflake.dir.clone());
+                StepPlan::run(
+                    "lockfile",
+                    &nix::lock::nix_flake_lock_check_cmd(nixcmd, &sub_flake_url),
+                )
+            }
+        }
+    }
+}
+
+/// Whether the node `key` of a lock file locks an instance of the input `name` (Nix disambiguates them as `nixpkgs`, `nixpkgs_2`, ...)
+fn is_instance_of(key: &str, name: &str) -> bool {
+    match key.strip_prefix(name) {
+        Some("") => true,
+        Some(suffix) => suffix
+            .strip_prefix('_')
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock() -> FlakeLock {
+        let github = |rev: &str, last_modified: i64| serde_json::json!({ "lastModified": last_modified, "narHash": "sha256-x", "owner": "o", "repo": "r", "rev": rev, "type": "github" });
+        serde_json::from_value(serde_json::json!({
+            "nodes": {
+                "haskell-flake": { "inputs": { "nixpkgs": "nixpkgs_2" }, "locked": github("a", 1_700_000_000) },
+                "nixpkgs": { "locked": github("b", 1_600_000_000) },
+                "nixpkgs_2": { "locked": github("c", 1_700_000_000) },
+                "nixpkgs-lib": { "locked": github("d", 1_700_000_000) },
+                "local": { "locked": { "lastModified": 1_700_000_000, "narHash": "sha256-x", "path": "/tmp/local", "type": "path" } },
+                "root": { "inputs": { "haskell-flake": "haskell-flake", "nixpkgs": "nixpkgs", "nixpkgs-lib": "nixpkgs-lib", "local": "local" } }
+            },
+            "root": "root",
+            "version": 7
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lockfile_policies() {
+        let step: LockfileStep = serde_json::from_value(serde_json::json!({
+            "enable": true,
+            "max-age": "30d",
+            "max-age-inputs": ["nixpkgs", "haskell-flake"],
+            "forbid-duplicates": ["nixpkgs"],
+            "forbid-unlocked": true,
+        }))
+        .unwrap();
+        assert_eq!(step.max_age, Some(Duration::from_secs(30 * 86400)));
+        let now = DateTime::from_timestamp(1_700_000_000 + 86400, 0).unwrap();
+        assert_eq!(
+            step.violations(&lock(), now),
+            vec![
+                "Input 'nixpkgs' was last modified 1158 days ago (2020-09-13), which is more than the max-age of 30days; run `nix flake update nixpkgs`",
+                "Input 'nixpkgs' is locked 2 times, as: nixpkgs, haskell-flake/nixpkgs; make them `follow` a single instance (e.g. `inputs.haskell-flake.inputs.nixpkgs.follows = \"nixpkgs\";`)",
+                "Input 'local' is a `path:` input (/tmp/local), which is not reproducible elsewhere",
+            ]
+        );
+        assert!(LockfileStep::default().violations(&lock(), now).is_empty());
+    }
+
+    #[test]
+    fn test_is_instance_of() {
+        assert!(is_instance_of("nixpkgs", "nixpkgs"));
+        assert!(is_instance_of("nixpkgs_12", "nixpkgs"));
+        assert!(!is_instance_of("nixpkgs-lib", "nixpkgs"));
+        assert!(!is_instance_of("nixpkgs_", "nixpkgs"));
+    }
 }
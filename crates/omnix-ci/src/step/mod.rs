@@ -1,6 +1,9 @@
 //! CI is broken down into various 'steps'.
 pub mod build;
+pub mod cache;
+pub mod closure_size;
 pub mod core;
 pub mod custom;
 pub mod flake_check;
+pub mod fmt;
 pub mod lockfile;
@@ -0,0 +1,181 @@
+//! Flake input updates made by `om ci update-lock`, and their Markdown report
+use std::fmt::Write;
+
+use chrono::DateTime;
+use nix_rs::flake::lock::{FlakeLock, LockInput, LockedRef};
+
+/// An input of a (sub)flake whose locked revision changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputUpdate {
+    /// Directory of the flake, relative to the root flake
+    pub dir: String,
+    /// Name of the input
+    pub input: String,
+    /// How the input was locked before the update
+    pub old: LockedRef,
+    /// How the input is locked after the update
+    pub new: LockedRef,
+}
+
+impl InputUpdate {
+    /// The updates of the inputs of the flake in `dir`, from its `old` to its `new` lock file
+    pub fn between(dir: &str, old: &FlakeLock, new: &FlakeLock) -> Vec<Self> {
+        let locked = |lock: &FlakeLock, input: &str| -> Option<LockedRef> {
+            match lock.root_node()?.inputs.get(input)? {
+                LockInput::Node(key) => lock.nodes.get(key)?.locked.clone(),
+                // Follows are not updated by themselves
+                LockInput::Follows(_) => None,
+            }
+        };
+        let Some(root) = new.root_node() else {
+            return vec![];
+        };
+        root.inputs
+            .keys()
+            .filter_map(|input| {
+                let old = locked(old, input)?;
+                let new = locked(new, input)?;
+                (old != new).then(|| InputUpdate {
+                    dir: dir.to_string(),
+                    input: input.clone(),
+                    old,
+                    new,
+                })
+            })
+            .collect()
+    }
+
+    /// Link to the changes between the old and new revisions, for `github:` inputs of the same repository
+    pub fn compare_url(&self) -> Option<String> {
+        let github = |r: &LockedRef| {
+            if r.type_ != "github" {
+                return None;
+            }
+            let owner = r.attrs.get("owner")?.as_str()?.to_string();
+            let repo = r.attrs.get("repo")?.as_str()?.to_string();
+            Some((owner, repo, r.rev.clone()?))
+        };
+        let (owner, repo, old_rev) = github(&self.old)?;
+        let (new_owner, new_repo, new_rev) = github(&self.new)?;
+        (owner == new_owner && repo == new_repo).then(|| {
+            format!(
+                "https://github.com/{}/{}/compare/{}...{}",
+                owner, repo, old_rev, new_rev
+            )
+        })
+    }
+}
+
+/// Short description of a locked input, such as `` `0123abc` (2024-05-01) ``
+fn describe(r: &LockedRef) -> String {
+    let id = match (&r.rev, &r.nar_hash) {
+        (Some(rev), _) => rev.chars().take(7).collect(),
+        (None, Some(hash)) => hash.clone(),
+        (None, None) => "?".to_string(),
+    };
+    match r.last_modified.and_then(|t| DateTime::from_timestamp(t, 0)) {
+        Some(t) => format!("`{}` ({})", id, t.format("%Y-%m-%d")),
+        None => format!("`{}`", id),
+    }
+}
+
+/// The outcome of `om ci run` on the updated inputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The run succeeded
+    Success,
+    /// The run failed, because of the given update (if it was found by bisecting)
+    Failure {
+        /// The update that breaks the build, if known
+        culprit: Option<Box<InputUpdate>>,
+    },
+}
+
+/// Render the updates and their outcome as Markdown
+pub fn to_markdown(updates: &[InputUpdate], outcome: &UpdateOutcome) -> String {
+    let mut md = String::new();
+    writeln!(md, "## Flake input updates\n").unwrap();
+    if updates.is_empty() {
+        writeln!(md, "All inputs are up to date.").unwrap();
+        return md;
+    }
+    writeln!(md, "| Flake | Input | Old | New | Changes |").unwrap();
+    writeln!(md, "| --- | --- | --- | --- | --- |").unwrap();
+    for u in updates {
+        let changes = u
+            .compare_url()
+            .map(|url| format!("[compare]({})", url))
+            .unwrap_or_default();
+        writeln!(
+            md,
+            "| `{}` | `{}` | {} | {} | {} |",
+            u.dir,
+            u.input,
+            describe(&u.old),
+            describe(&u.new),
+            changes
+        )
+        .unwrap();
+    }
+    writeln!(md).unwrap();
+    match outcome {
+        UpdateOutcome::Success => writeln!(md, "✅ `om ci run` succeeded.").unwrap(),
+        UpdateOutcome::Failure { culprit: None } => writeln!(md, "❌ `om ci run` failed.").unwrap(),
+        UpdateOutcome::Failure {
+            culprit: Some(culprit),
+        } => writeln!(
+            md,
+            "❌ `om ci run` failed, because of the update of `{}` in `{}`.",
+            culprit.input, culprit.dir
+        )
+        .unwrap(),
+    }
+    md
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(nixpkgs_rev: &str, last_modified: i64) -> FlakeLock {
+        serde_json::from_value(serde_json::json!({
+            "nodes": {
+                "nixpkgs": {
+                    "locked": { "lastModified": last_modified, "narHash": "sha256-x", "owner": "NixOS", "repo": "nixpkgs", "rev": nixpkgs_rev, "type": "github" }
+                },
+                "local": {
+                    "locked": { "lastModified": 1_700_000_000, "narHash": "sha256-y", "path": "/tmp/local", "type": "path" }
+                },
+                "root": { "inputs": { "nixpkgs": "nixpkgs", "local": "local" } }
+            },
+            "root": "root",
+            "version": 7
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_update_report() {
+        let old = lock("0123456789abcdef", 1_700_000_000);
+        let new = lock("fedcba9876543210", 1_710_000_000);
+        let updates = InputUpdate::between(".", &old, &new);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(
+            to_markdown(
+                &updates,
+                &UpdateOutcome::Failure {
+                    culprit: Some(Box::new(updates[0].clone()))
+                }
+            ),
+            "## Flake input updates
+
+| Flake | Input | Old | New | Changes |
+| --- | --- | --- | --- | --- |
+| `.` | `nixpkgs` | `0123456` (2023-11-14) | `fedcba9` (2024-03-09) | [compare](https://github.com/NixOS/nixpkgs/compare/0123456789abcdef...fedcba9876543210) |
+
+❌ `om ci run` failed, because of the update of `nixpkgs` in `.`.
+"
+        );
+        assert!(InputUpdate::between(".", &old, &old).is_empty());
+    }
+}
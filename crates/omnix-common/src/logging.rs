@@ -76,7 +76,7 @@ fn log_directives(v: &Verbosity<InfoLevel>) -> Vec<Directive> {
 }
 
 /// A [tracing_subscriber] event formatter that suppresses everything but the
-/// log message.
+/// log message (and the [nix_rs::command::log_prefix], if any).
 struct BareFormatter;
 
 impl<S, N> FormatEvent<S, N> for BareFormatter
@@ -96,6 +96,9 @@ where
             write!(&mut writer, "{} {}: ", metadata.level(), metadata.target())?;
         }
         */
+        if let Some(prefix) = nix_rs::command::log_prefix() {
+            write!(writer, "{} ", prefix)?;
+        }
         ctx.field_format().format_fields(writer.by_ref(), event)?;
         writeln!(writer)
     }
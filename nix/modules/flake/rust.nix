@@ -43,6 +43,7 @@
             || lib.hasSuffix "addstringcontext/flake.lock" path
             || lib.hasSuffix "metadata/flake.nix" path
             || lib.hasSuffix "metadata/flake.lock" path
+            || lib.hasSuffix "omnix-ci/src/nix/filter-flake.nix" path
           ;
         };
       defaults.perCrate.crane.args = import "${inputs.self}/nix/envs" {